@@ -0,0 +1,89 @@
+use std::{
+    ffi::OsString,
+    io::{self, Write},
+    path::Path,
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{Error, Package, Result};
+
+/// Outcome of running cargo update for the downgraded crates
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    /// Packages that have been downgraded in Cargo.lock
+    pub downgraded: Vec<Package>,
+    /// Packages that cargo update failed for, with the error message of cargo
+    pub failed: Vec<(Package, String)>,
+}
+
+/// Arguments of the cargo update command that downgrades `package` in the project of the Cargo.toml at
+/// `manifest_path`
+pub fn cargo_update_arguments(package: &Package, manifest_path: &Path) -> Vec<OsString> {
+    vec![
+        "update".into(),
+        "--manifest-path".into(),
+        manifest_path.into(),
+        "-p".into(),
+        // name@version picks the right one if the crate is locked in several versions
+        match &package.locked {
+            Some(locked) => format!("{}@{}", package.name, locked),
+            None => package.name.clone(),
+        }
+        .into(),
+        "--precise".into(),
+        package.version.clone().into(),
+    ]
+}
+
+/// Downgrade `packages` in the Cargo.lock of the project in `manifest_dir` by running cargo update for each
+/// of them. The output of cargo is passed through to stderr.
+///
+/// A failing cargo update does not stop the others, it is recorded in the report instead. Once `stop` is
+/// set, e.g. by a Ctrl-C handler, no further cargo update is started and [`Error::Interrupted`] is returned,
+/// leaving Cargo.lock partially downgraded.
+pub fn apply_downgrades(
+    packages: &[Package],
+    manifest_dir: &Path,
+    stop: &AtomicBool,
+) -> Result<ApplyReport> {
+    let manifest_path = manifest_dir.join("Cargo.toml");
+    let mut report = ApplyReport::default();
+    // cargo update takes a single --precise version per invocation, which would apply to every -p given,
+    // so the crates are updated one at a time
+    for package in packages {
+        if stop.load(Ordering::Relaxed) {
+            return Err(Error::Interrupted);
+        }
+        let output = Command::new("cargo")
+            .args(cargo_update_arguments(package, &manifest_path))
+            .output()
+            .map_err(Error::CargoUpdate)?;
+
+        // stdout is left to the caller, e.g. for a machine-readable plan
+        let mut stderr = io::stderr();
+        let _ = stderr.write_all(&output.stdout);
+        let _ = stderr.write_all(&output.stderr);
+        // cargo has been interrupted as well, its failure is not worth reporting
+        if stop.load(Ordering::Relaxed) {
+            return Err(Error::Interrupted);
+        }
+
+        if output.status.success() {
+            report.downgraded.push(package.clone());
+        } else {
+            let message = update_error(&String::from_utf8_lossy(&output.stderr));
+            report.failed.push((package.clone(), message));
+        }
+    }
+    Ok(report)
+}
+
+/// The first error message in the output of cargo, or the whole output if there is none
+fn update_error(stderr: &str) -> String {
+    stderr
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("error: "))
+        .unwrap_or(stderr.trim())
+        .to_owned()
+}
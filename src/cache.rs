@@ -0,0 +1,165 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::PublishedVersion;
+use log::{debug, warn};
+
+/// On-disk cache of the version metadata fetched from crates.io, one JSON file per crate
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    ttl: Duration,
+    refresh: bool,
+}
+
+impl Cache {
+    /// Cache in `dir` whose entries are considered stale after `ttl`
+    pub fn new(dir: PathBuf, ttl: Duration) -> Self {
+        Cache {
+            dir,
+            ttl,
+            refresh: false,
+        }
+    }
+
+    /// Ignore existing entries and re-fetch everything, while still updating the cache
+    pub fn refresh(mut self, refresh: bool) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    /// The platform cache directory, e.g. `~/.cache/cargo-downgrade` on Linux
+    pub fn default_dir() -> Option<PathBuf> {
+        platform_cache_dir().map(|dir| dir.join("cargo-downgrade"))
+    }
+
+    fn path(&self, crate_name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", crate_name))
+    }
+
+    /// Load the cached versions of `crate_name` if present and not older than the TTL
+    pub fn load(&self, crate_name: &str) -> Option<Vec<PublishedVersion>> {
+        if self.refresh {
+            return None;
+        }
+
+        let path = self.path(crate_name);
+        let age = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())?;
+        if age > self.ttl {
+            debug!("cache entry for crate {} is stale", crate_name);
+            return None;
+        }
+
+        let content = fs::read(&path).ok()?;
+        match serde_json::from_slice(&content) {
+            Ok(versions) => {
+                debug!("loaded crate {} from cache", crate_name);
+                Some(versions)
+            }
+            Err(err) => {
+                warn!("ignoring corrupt cache entry {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Store the versions of `crate_name`. Failures are logged but otherwise ignored.
+    pub fn store(&self, crate_name: &str, versions: &[PublishedVersion]) {
+        if let Err(err) = write_entry(&self.path(crate_name), versions) {
+            warn!("failed to cache crate {}: {}", crate_name, err);
+        }
+    }
+}
+
+fn write_entry(path: &Path, versions: &[PublishedVersion]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let content = serde_json::to_vec(versions)?;
+    fs::write(path, content)
+}
+
+#[cfg(target_os = "macos")]
+fn platform_cache_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join("Library/Caches"))
+}
+
+#[cfg(windows)]
+fn platform_cache_dir() -> Option<PathBuf> {
+    std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn platform_cache_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn versions() -> Vec<PublishedVersion> {
+        vec![PublishedVersion {
+            num: "1.0.123".to_owned(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            yanked: false,
+            rust_version: None,
+            checksum: None,
+        }]
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().to_owned(), Duration::from_secs(60 * 60));
+        assert!(cache.load("serde").is_none());
+        cache.store("serde", &versions());
+        assert_eq!(cache.load("serde").unwrap()[0].num, "1.0.123");
+
+        // an entry written two hours ago is stale after an hour, but not after three
+        let two_hours_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        fs::File::options()
+            .write(true)
+            .open(cache.path("serde"))
+            .unwrap()
+            .set_modified(two_hours_ago)
+            .unwrap();
+        assert!(cache.load("serde").is_none());
+        let cache = Cache::new(dir.path().to_owned(), Duration::from_secs(3 * 60 * 60));
+        assert!(cache.load("serde").is_some());
+    }
+
+    #[test]
+    fn test_cache_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().to_owned(), Duration::from_secs(60 * 60)).refresh(true);
+        cache.store("serde", &versions());
+        // the entry is written, but not read back
+        assert!(cache.path("serde").is_file());
+        assert!(cache.load("serde").is_none());
+        assert!(cache.refresh(false).load("serde").is_some());
+    }
+
+    #[test]
+    fn test_cache_keys_of_registries() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().to_owned(), Duration::from_secs(60 * 60));
+        // the key of a mirror has the host as a directory, see `RegistryClient::cache_key`
+        cache.store("mirror.example.com/serde", &versions());
+        assert!(dir.path().join("mirror.example.com/serde.json").is_file());
+        assert!(cache.load("mirror.example.com/serde").is_some());
+        assert!(cache.load("serde").is_none());
+    }
+}
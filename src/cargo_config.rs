@@ -0,0 +1,204 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use toml::Table;
+
+use crate::{Error, Result};
+
+/// Name of crates.io in the `[source]` tables of the cargo configuration
+const CRATES_IO: &str = "crates-io";
+
+/// Where cargo looks up crates.io crates instead, as configured with `[source.crates-io] replace-with`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceReplacement {
+    /// A registry with the index at this URL, e.g. `sparse+https://mirror.example.com/index/`
+    Registry(String),
+    /// A local registry in this directory, created e.g. by cargo-local-registry
+    LocalRegistry(PathBuf),
+    /// A directory of vendored crates, created e.g. by cargo vendor
+    Directory(PathBuf),
+}
+
+/// A parsed configuration file, with the directory its relative paths are relative to
+struct ConfigFile {
+    base: PathBuf,
+    table: Table,
+}
+
+impl ConfigFile {
+    fn get(&self, section: &str, name: &str, key: &str) -> Option<&str> {
+        self.table.get(section)?.get(name)?.get(key)?.as_str()
+    }
+}
+
+/// The replacement of crates.io in the cargo configuration that applies to a project in `dir`, i.e. in
+/// `.cargo/config.toml` in `dir` or one of its parents, or in `$CARGO_HOME/config.toml`. Like in cargo,
+/// the files closer to `dir` take precedence.
+pub fn crates_io_replacement(dir: &Path) -> Result<Option<SourceReplacement>> {
+    // the parents of a relative path like the empty parent of `Cargo.lock` are not known otherwise
+    let dir = std::env::current_dir()
+        .map(|current_dir| current_dir.join(dir))
+        .unwrap_or_else(|_| dir.to_owned());
+    let mut config_dirs: Vec<PathBuf> = dir.ancestors().map(|dir| dir.join(".cargo")).collect();
+    if let Some(cargo_home) = cargo_home() {
+        config_dirs.push(cargo_home);
+    }
+    let mut configs = vec![];
+    for config_dir in config_dirs {
+        // cargo still reads `config` without extension, but prefers `config.toml`
+        for path in [config_dir.join("config.toml"), config_dir.join("config")] {
+            if let Some(table) = read_config(&path)? {
+                let base = config_dir.parent().unwrap_or(Path::new("")).to_owned();
+                configs.push(ConfigFile { base, table });
+                break;
+            }
+        }
+    }
+    replacement(&configs)
+}
+
+fn replacement(configs: &[ConfigFile]) -> Result<Option<SourceReplacement>> {
+    let lookup = |section: &str, name: &str, key: &str| {
+        configs
+            .iter()
+            .find_map(|config| Some((config, config.get(section, name, key)?)))
+    };
+
+    let Some((_, mut name)) = lookup("source", CRATES_IO, "replace-with") else {
+        return Ok(None);
+    };
+    // a replacement may be replaced itself
+    let mut seen = vec![CRATES_IO];
+    while let Some((_, next)) = lookup("source", name, "replace-with") {
+        if seen.contains(&name) {
+            return Err(Error::SourceReplacementCycle(name.to_owned()));
+        }
+        seen.push(name);
+        name = next;
+    }
+
+    if let Some((_, url)) = lookup("source", name, "registry") {
+        Ok(Some(SourceReplacement::Registry(url.to_owned())))
+    } else if let Some((config, path)) = lookup("source", name, "local-registry") {
+        Ok(Some(SourceReplacement::LocalRegistry(
+            config.base.join(path),
+        )))
+    } else if let Some((config, path)) = lookup("source", name, "directory") {
+        Ok(Some(SourceReplacement::Directory(config.base.join(path))))
+    } else if let Some((_, url)) = lookup("registries", name, "index") {
+        Ok(Some(SourceReplacement::Registry(url.to_owned())))
+    } else {
+        Err(Error::UnknownSource(name.to_owned()))
+    }
+}
+
+/// The configuration file at `path`, or `None` if there is none
+fn read_config(path: &Path) -> Result<Option<Table>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::ReadCargoConfig(path.to_owned(), err)),
+    };
+    content
+        .parse()
+        .map(Some)
+        .map_err(|err| Error::ParseCargoConfig(path.to_owned(), Box::new(err)))
+}
+
+/// `$CARGO_HOME`, or `~/.cargo` if it is not set
+pub(crate) fn cargo_home() -> Option<PathBuf> {
+    std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(base: &str, content: &str) -> ConfigFile {
+        ConfigFile {
+            base: PathBuf::from(base),
+            table: content.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_crates_io_replacement() {
+        let project = config(
+            "/work/project",
+            r#"
+[source.crates-io]
+replace-with = "vendored"
+
+[source.vendored]
+directory = "vendor"
+"#,
+        );
+        let home = config(
+            "/home/user",
+            r#"
+[source.crates-io]
+replace-with = "mirror"
+
+[source.mirror]
+registry = "sparse+https://mirror.example.com/index/"
+
+[registries.company]
+index = "sparse+https://registry.example.com/index/"
+"#,
+        );
+
+        // the project configuration takes precedence
+        assert_eq!(
+            replacement(&[project, home]).unwrap(),
+            Some(SourceReplacement::Directory(PathBuf::from(
+                "/work/project/vendor"
+            )))
+        );
+        let home = config(
+            "/home/user",
+            r#"
+[source.crates-io]
+replace-with = "mirror"
+
+[source.mirror]
+registry = "sparse+https://mirror.example.com/index/"
+"#,
+        );
+        assert_eq!(
+            replacement(std::slice::from_ref(&home)).unwrap(),
+            Some(SourceReplacement::Registry(
+                "sparse+https://mirror.example.com/index/".to_owned()
+            ))
+        );
+
+        let registry = config(
+            "/home/user",
+            "source.crates-io.replace-with = \"company\"\n\n[registries.company]\nindex = \"sparse+https://registry.example.com/index/\"\n",
+        );
+        assert_eq!(
+            replacement(&[registry]).unwrap(),
+            Some(SourceReplacement::Registry(
+                "sparse+https://registry.example.com/index/".to_owned()
+            ))
+        );
+
+        let cycle = config(
+            "/",
+            "source.crates-io.replace-with = \"a\"\nsource.a.replace-with = \"b\"\nsource.b.replace-with = \"a\"\n",
+        );
+        assert!(matches!(
+            replacement(&[cycle]),
+            Err(Error::SourceReplacementCycle(name)) if name == "a"
+        ));
+        let unknown = config("/", "source.crates-io.replace-with = \"nowhere\"\n");
+        assert!(matches!(
+            replacement(&[unknown]),
+            Err(Error::UnknownSource(name)) if name == "nowhere"
+        ));
+        assert_eq!(replacement(&[config("/", "")]).unwrap(), None);
+    }
+}
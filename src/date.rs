@@ -0,0 +1,94 @@
+use chrono::{DateTime, Months, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Utc};
+
+use crate::{Error, Result};
+
+/// Parse a date in any of the accepted formats. Dates without a timezone are taken as UTC,
+/// dates without a time as the start of that day.
+pub fn parse_date(date: &str) -> Result<DateTime<Utc>> {
+    if let Some(datetime) = parse_relative_date(date, Utc::now()) {
+        return Ok(datetime);
+    }
+    DateTime::parse_from_rfc3339(date)
+        .or_else(|_| DateTime::parse_from_rfc2822(date))
+        .map(|datetime| datetime.with_timezone(&Utc))
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S")
+                .map(|datetime| datetime.and_utc())
+        })
+        .or_else(|_| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map(|date| date.and_time(NaiveTime::MIN).and_utc())
+        })
+        .map_err(|_| Error::DateParse(date.to_owned()))
+}
+
+/// Parse a date relative to `now` such as "6 months ago". Months and years are calendar months and years,
+/// the other units have a fixed length.
+fn parse_relative_date(date: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let [amount, unit, "ago"] = date.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let amount: u32 = amount.parse().ok()?;
+    let seconds = |unit_seconds: i64| {
+        now.checked_sub_signed(TimeDelta::seconds(unit_seconds * i64::from(amount)))
+    };
+    match unit.strip_suffix('s').unwrap_or(unit) {
+        "second" => seconds(1),
+        "minute" => seconds(60),
+        "hour" => seconds(60 * 60),
+        "day" => seconds(24 * 60 * 60),
+        "week" => seconds(7 * 24 * 60 * 60),
+        "month" => now.checked_sub_months(Months::new(amount)),
+        "year" => now.checked_sub_months(Months::new(amount.checked_mul(12)?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        let expected = DateTime::parse_from_rfc3339("2021-02-22T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(parse_date("2021-02-22").unwrap(), expected);
+        assert_eq!(parse_date("2021-02-22T00:00:00Z").unwrap(), expected);
+        assert_eq!(parse_date("22 Feb 2021 00:00:00 GMT").unwrap(), expected);
+        assert!(parse_date("2021-02-30").is_err());
+        assert!(parse_date("22.02.2021").is_err());
+    }
+
+    #[test]
+    fn test_parse_relative_date() {
+        let now = DateTime::parse_from_rfc3339("2021-08-31T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let date = |date| parse_relative_date(date, now).map(|date| date.to_rfc3339());
+        assert_eq!(
+            date("2 weeks ago").as_deref(),
+            Some("2021-08-17T12:00:00+00:00")
+        );
+        assert_eq!(
+            date("1 day ago").as_deref(),
+            Some("2021-08-30T12:00:00+00:00")
+        );
+        // calendar months, clamped to the end of shorter months
+        assert_eq!(
+            date("6 months ago").as_deref(),
+            Some("2021-02-28T12:00:00+00:00")
+        );
+        assert_eq!(
+            date("1 year ago").as_deref(),
+            Some("2020-08-31T12:00:00+00:00")
+        );
+        assert_eq!(date("2 fortnights ago"), None);
+        assert_eq!(date("in 2 weeks"), None);
+
+        // relative to the current time
+        let before = Utc::now() - TimeDelta::weeks(2);
+        let parsed = parse_date("2 weeks ago").unwrap();
+        assert!(before <= parsed && parsed <= Utc::now() - TimeDelta::weeks(2));
+    }
+}
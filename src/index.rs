@@ -0,0 +1,255 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use futures::{FutureExt, future::BoxFuture};
+use log::warn;
+use serde::Deserialize;
+
+use crate::{Error, PublishedVersion, Result, VersionSource};
+
+/// A local copy of a sparse registry index, e.g. a mirror of `https://index.crates.io/`
+#[derive(Debug, Clone)]
+pub struct SparseIndex {
+    root: PathBuf,
+}
+
+/// A line of an index file. Only `pubtime` tells when a version has been published.
+#[derive(Deserialize)]
+struct IndexEntry {
+    vers: String,
+    cksum: String,
+    yanked: bool,
+    #[serde(default)]
+    rust_version: Option<String>,
+    #[serde(default)]
+    pubtime: Option<DateTime<Utc>>,
+}
+
+impl SparseIndex {
+    /// Index rooted at `root`, the directory that contains `config.json`
+    pub fn new(root: PathBuf) -> Self {
+        SparseIndex { root }
+    }
+
+    /// Path of the index file of `crate_name`, see <https://doc.rust-lang.org/cargo/reference/registry-index.html#index-files>
+    fn entry_path(&self, crate_name: &str) -> PathBuf {
+        let name = crate_name.to_lowercase();
+        let path = match name.len() {
+            1 => Path::new("1").join(&name),
+            2 => Path::new("2").join(&name),
+            3 => Path::new("3").join(&name[..1]).join(&name),
+            _ => Path::new(&name[..2]).join(&name[2..4]).join(&name),
+        };
+        self.root.join(path)
+    }
+
+    async fn read_versions(&self, crate_name: &str) -> Result<Vec<PublishedVersion>> {
+        let path = self.entry_path(crate_name);
+        let content = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::CrateNotFound(crate_name.to_owned()));
+            }
+            Err(err) => return Err(Error::ReadIndex(path, err)),
+        };
+        let lines = content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::as_bytes);
+        parse_entries(lines, crate_name, &path)
+    }
+}
+
+/// Turn the JSON `entries` of the index file at `path` into the published versions of `crate_name`
+fn parse_entries<'a>(
+    entries: impl Iterator<Item = &'a [u8]>,
+    crate_name: &str,
+    path: &Path,
+) -> Result<Vec<PublishedVersion>> {
+    let mut versions = vec![];
+    let mut without_pubtime = 0;
+    for entry in entries {
+        let entry: IndexEntry = serde_json::from_slice(entry)?;
+        match entry.pubtime {
+            Some(pubtime) => versions.push(PublishedVersion {
+                num: entry.vers,
+                created_at: pubtime,
+                updated_at: pubtime,
+                yanked: entry.yanked,
+                rust_version: entry.rust_version,
+                checksum: Some(entry.cksum),
+            }),
+            None => without_pubtime += 1,
+        }
+    }
+    if without_pubtime > 0 {
+        warn!(
+            "ignoring {} versions of crate {} without publish time in {}",
+            without_pubtime,
+            crate_name,
+            path.display()
+        );
+    }
+
+    Ok(versions)
+}
+
+/// The copy of the crates.io index that cargo keeps in `$CARGO_HOME/registry/index` for the crates it has
+/// resolved before, which allows looking up versions without network access
+#[derive(Debug, Clone)]
+pub struct CargoIndexCache {
+    index: SparseIndex,
+}
+
+/// Version of the format of cargo's index cache files that we can read
+const CACHE_VERSION: u8 = 3;
+
+impl CargoIndexCache {
+    /// The cache of the crates.io index in `cargo_home`, e.g. `~/.cargo`
+    pub fn new(cargo_home: &Path) -> Result<Self> {
+        let registries = cargo_home.join("registry").join("index");
+        let no_cache = || Error::NoCargoCache(registries.clone());
+        // cargo versions that hash the index URL differently keep their caches side by side, take the newest one
+        let root = std::fs::read_dir(&registries)
+            .map_err(|_| no_cache())?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("index.crates.io-")
+            })
+            .map(|entry| entry.path().join(".cache"))
+            .filter(|cache| cache.is_dir())
+            .max_by_key(|cache| {
+                cache
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .ok_or_else(no_cache)?;
+        Ok(CargoIndexCache {
+            index: SparseIndex::new(root),
+        })
+    }
+
+    /// The cache in `$CARGO_HOME`, or `~/.cargo` if it is not set
+    pub fn from_env() -> Result<Self> {
+        let cargo_home = crate::cargo_config::cargo_home()
+            .ok_or_else(|| Error::NoCargoCache(PathBuf::from("~/.cargo")))?;
+        Self::new(&cargo_home)
+    }
+
+    async fn read_versions(&self, crate_name: &str) -> Result<Vec<PublishedVersion>> {
+        let path = self.index.entry_path(crate_name);
+        let content = match tokio::fs::read(&path).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::NotInCargoCache(crate_name.to_owned()));
+            }
+            Err(err) => return Err(Error::ReadIndex(path, err)),
+        };
+
+        // a cache version byte and the index format as u32, followed by the NUL terminated index version
+        // and then pairs of NUL terminated version numbers and JSON entries
+        if content.first() != Some(&CACHE_VERSION) || content.len() < 5 {
+            return Err(Error::CacheFormat(path));
+        }
+        let mut fields = content[5..].split(|&byte| byte == 0).skip(1);
+        let entries = std::iter::from_fn(|| {
+            let _version = fields.next()?;
+            fields.next()
+        })
+        .filter(|entry| !entry.is_empty());
+        parse_entries(entries, crate_name, &path)
+    }
+}
+
+impl fmt::Display for CargoIndexCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.index)
+    }
+}
+
+impl VersionSource for CargoIndexCache {
+    fn versions<'a>(&'a self, crate_name: &'a str) -> BoxFuture<'a, Result<Vec<PublishedVersion>>> {
+        self.read_versions(crate_name).boxed()
+    }
+}
+
+impl fmt::Display for SparseIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.root.display())
+    }
+}
+
+impl VersionSource for SparseIndex {
+    fn versions<'a>(&'a self, crate_name: &'a str) -> BoxFuture<'a, Result<Vec<PublishedVersion>>> {
+        self.read_versions(crate_name).boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_versions() {
+        let root = tempfile::tempdir().unwrap();
+        let index = SparseIndex::new(root.path().to_owned());
+        let path = index.entry_path("serde");
+        assert!(path.ends_with("se/rd/serde"));
+
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(
+            &path,
+            concat!(
+                r#"{"name":"serde","vers":"1.0.122","deps":[],"cksum":"aa","features":{},"yanked":false}"#,
+                "\n",
+                r#"{"name":"serde","vers":"1.0.123","deps":[],"cksum":"bb","features":{},"yanked":true,"pubtime":"2021-01-26T00:00:00Z"}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+
+        let versions = index.versions("serde").await.unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].num, "1.0.123");
+        assert!(versions[0].yanked);
+        assert_eq!(versions[0].checksum.as_deref(), Some("bb"));
+
+        assert!(matches!(
+            index.versions("serde_typo").await,
+            Err(Error::CrateNotFound(name)) if name == "serde_typo"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_cargo_index_cache() {
+        let cargo_home = tempfile::tempdir().unwrap();
+        let cargo_home = cargo_home.path();
+        let root = cargo_home.join("registry/index/index.crates.io-1949cf8c6b5b557f/.cache");
+        let path = SparseIndex::new(root).entry_path("log");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut content = vec![CACHE_VERSION, 2, 0, 0, 0];
+        content.extend_from_slice(b"Unknown\0");
+        content.extend_from_slice(b"0.4.13\0");
+        content.extend_from_slice(
+            br#"{"name":"log","vers":"0.4.13","deps":[],"cksum":"cc","features":{},"yanked":false,"pubtime":"2021-01-11T00:00:00Z"}"#,
+        );
+        content.push(0);
+        std::fs::write(&path, content).unwrap();
+
+        let cache = CargoIndexCache::new(cargo_home).unwrap();
+        let versions = cache.versions("log").await.unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].num, "0.4.13");
+        assert!(matches!(
+            cache.versions("serde").await,
+            Err(Error::NotInCargoCache(_))
+        ));
+    }
+}
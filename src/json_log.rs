@@ -0,0 +1,96 @@
+//! Logger that writes every record as a JSON object on its own line to stderr, for log aggregation
+
+use std::io::{self, Write};
+
+use chrono::{SecondsFormat, Utc};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde::Serialize;
+
+pub struct JsonLogger {
+    level: LevelFilter,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+}
+
+impl JsonLogger {
+    /// Logger for records up to `level`, or up to the level in RUST_LOG if it is set, like simple_logger
+    pub fn new(level: LevelFilter) -> Self {
+        let level = std::env::var("RUST_LOG")
+            .ok()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(level);
+        JsonLogger { level }
+    }
+
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        log::set_max_level(self.level);
+        log::set_boxed_logger(Box::new(self))
+    }
+}
+
+/// `record` as a JSON object on a line of its own
+fn json_line(record: &Record) -> Vec<u8> {
+    let record = JsonRecord {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        level: record.level().as_str(),
+        target: record.target(),
+        message: record.args().to_string(),
+    };
+    let mut line = serde_json::to_vec(&record).unwrap_or_default();
+    line.push(b'\n');
+    line
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // a single write per line, so that records from several threads are not interleaved
+        let _ = io::stderr().lock().write_all(&json_line(record));
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn test_json_line() {
+        let line = json_line(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("cargo_downgrade")
+                .args(format_args!("crate \"foo\" failed:\n\ttimeout"))
+                .build(),
+        );
+        let line = String::from_utf8(line).unwrap();
+        // the newline in the message is escaped, so that every record is a single line
+        assert_eq!(line.lines().count(), 1);
+        assert!(line.ends_with('\n'));
+
+        let object: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(object["level"], "WARN");
+        assert_eq!(object["target"], "cargo_downgrade");
+        assert_eq!(object["message"], "crate \"foo\" failed:\n\ttimeout");
+        let timestamp = object["timestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+        assert_eq!(object.as_object().unwrap().len(), 4);
+    }
+}
@@ -1,50 +1,481 @@
 use core::fmt;
-use std::{collections::HashSet, num::NonZeroU8};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write,
+    num::{NonZeroU8, NonZeroUsize},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 
+use cargo_lock::{Lockfile, SourceId};
 use chrono::{DateTime, Utc};
-use crates_io_api::Version;
-use log::{error, info};
+use futures::{StreamExt, stream};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, error, info, warn};
+use serde::Serialize;
 use thiserror::Error;
 
-#[derive(Debug)]
+mod apply;
+mod cache;
+mod cargo_config;
+mod date;
+mod index;
+mod lockfile;
+mod manifest;
+mod registry;
+mod source;
+
+pub use apply::{ApplyReport, apply_downgrades, cargo_update_arguments};
+pub use cache::Cache;
+pub use cargo_config::{SourceReplacement, crates_io_replacement};
+pub use date::parse_date;
+pub use index::{CargoIndexCache, SparseIndex};
+pub use lockfile::{downgrade_lockfile, load_lockfile, versions_from_snapshot};
+pub use manifest::pin_direct_dependencies;
+pub use registry::{CRATES_IO_API, RegistryClient};
+pub use source::{PublishedVersion, VersionSource};
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Package {
     pub name: String,
     pub version: String,
+    /// The version the crate is currently locked to, if it is in the lockfile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locked: Option<String>,
+    /// SHA-256 checksum of the `.crate` file of `version`, if the registry provides it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
     /* source: Option<String>,
     dependencies: Option<HashMap<String, Value>>, */
 }
 
+impl Package {
+    /// The requirement `=<version>` that pins the crate to exactly this version, as in the `Display` output
+    pub fn version_req(&self) -> Result<semver::VersionReq> {
+        semver::VersionReq::parse(&format!("={}", self.version))
+            .map_err(|err| Error::InvalidVersion(self.version.clone(), err))
+    }
+}
+
 impl fmt::Display for Package {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{} = \"={}\"", self.name, self.version)
     }
 }
 
+/// Format `packages` as a `[patch.crates-io]` table that pins every crate to its exact version.
+/// Crates that appear more than once are only emitted once.
+pub fn to_patch_table(packages: &[Package]) -> String {
+    let mut pins: BTreeMap<&str, &str> = BTreeMap::new();
+    for package in packages {
+        match pins.get(package.name.as_str()) {
+            Some(version) if *version != package.version => warn!(
+                "crate {} is pinned to both {} and {}, keeping {}",
+                package.name, version, package.version, version
+            ),
+            Some(_) => {}
+            None => {
+                pins.insert(&package.name, &package.version);
+            }
+        }
+    }
+
+    let mut table = String::from("[patch.crates-io]\n");
+    for (name, version) in pins {
+        writeln!(table, "{} = {{ version = \"={}\" }}", name, version).unwrap();
+    }
+    table
+}
+
+/// Format the part of `dependency_tree` that consists of local crates and the crates in `crate_names` as a
+/// Graphviz DOT graph
+pub fn to_dot(
+    dependency_tree: &cargo_lock::dependency::Tree,
+    crate_names: &HashSet<&str>,
+) -> String {
+    let graph = dependency_tree.graph();
+    let included = |node_index: petgraph::prelude::NodeIndex| {
+        let package = &graph[node_index];
+        package.source.is_none() || crate_names.contains(package.name.as_str())
+    };
+
+    let mut dot = String::from("digraph dependencies {\n");
+    for node_index in graph
+        .node_indices()
+        .filter(|&node_index| included(node_index))
+    {
+        let package = &graph[node_index];
+        writeln!(
+            dot,
+            "    {} [label=\"{} {}\"];",
+            node_index.index(),
+            package.name,
+            package.version
+        )
+        .unwrap();
+    }
+    for edge in graph.raw_edges() {
+        if included(edge.source()) && included(edge.target()) {
+            writeln!(
+                dot,
+                "    {} -> {};",
+                edge.source().index(),
+                edge.target().index()
+            )
+            .unwrap();
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Whether two crate names refer to the same crate. Like crates.io, `-` and `_` are treated as the same
+/// character.
+pub fn same_crate_name(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.chars().zip(b.chars()).all(|(a, b)| same_char(a, b))
+}
+
+fn same_char(a: char, b: char) -> bool {
+    a == b || (matches!(a, '-' | '_') && matches!(b, '-' | '_'))
+}
+
+/// The value of crate `name` in `map`, whose keys may differ from `name` in `-` and `_`
+fn get_by_crate_name<'m, V>(map: &'m HashMap<String, V>, name: &str) -> Option<&'m V> {
+    map.get(name).or_else(|| {
+        map.iter()
+            .find(|(key, _)| same_crate_name(key, name))
+            .map(|(_, value)| value)
+    })
+}
+
+/// Longest crate name that crates.io accepts
+const MAX_CRATE_NAME_LENGTH: usize = 64;
+
+/// Check `name` against the naming rules of crates.io: up to 64 ASCII letters, digits, `-` and `_`, starting
+/// with a letter
+pub fn validate_crate_name(name: &str) -> Result<()> {
+    let invalid = |reason| Err(Error::InvalidCrateName(name.to_owned(), reason));
+    match name.chars().next() {
+        None => return invalid("it is empty"),
+        Some(first) if !first.is_ascii_alphabetic() => {
+            return invalid("it does not start with a letter");
+        }
+        Some(_) => {}
+    }
+    if name.len() > MAX_CRATE_NAME_LENGTH {
+        return invalid("it is longer than 64 characters");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+    {
+        return invalid("only letters, digits, - and _ are allowed");
+    }
+    Ok(())
+}
+
+/// The spelling of crate `name` in `lockfile`, which may differ from `name` in `-` and `_`, or `name`
+/// itself if the crate is not locked
+pub fn canonical_crate_name<'a>(lockfile: &'a Lockfile, name: &'a str) -> &'a str {
+    lockfile
+        .packages
+        .iter()
+        .find(|package| same_crate_name(package.name.as_str(), name))
+        .map_or(name, |package| package.name.as_str())
+}
+
+/// Match `name` against a glob `pattern` in which `*` matches any sequence of characters and `?` matches a single character.
+/// `-` and `_` match each other.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // position after the last `*` in the pattern and in the name, to backtrack to when a match fails
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || same_char(pattern[p], name[n])) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            backtrack = Some((p, n));
+        } else if let Some((star_p, star_n)) = backtrack {
+            // let the last `*` consume one more character
+            p = star_p;
+            n = star_n + 1;
+            backtrack = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Failed to read Cargo.lock")]
     ReadCargoLock(#[from] std::io::Error),
     #[error("Failed to parse Cargo.lock")]
     ParseCargoLock(#[from] cargo_lock::Error),
-    #[error("Failed to fetch from crates.io")]
-    Reqwest(#[from] crates_io_api::Error),
+    #[error("Failed to fetch from the registry")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("Failed to decode the registry response")]
+    Decode(#[from] serde_json::Error),
+    #[error("Failed to connect to the registry through proxy {0}")]
+    Proxy(String, #[source] reqwest::Error),
+    #[error("Invalid registry URL {0}")]
+    RegistryUrl(String, #[source] url::ParseError),
+    #[error(
+        "Invalid date {0:?}, expected YYYY-MM-DD, RFC 3339 format, e.g. \"2021-02-22T23:16:09Z\", RFC 2822 format, e.g. \"22 Feb 2021 23:16:09 GMT\", or a relative date, e.g. \"2 weeks ago\""
+    )]
+    DateParse(String),
+    #[error("Invalid crate name {0:?}, {1}")]
+    InvalidCrateName(String, &'static str),
+    #[error("Crate {0} not found in the registry")]
+    CrateNotFound(String),
+    #[error("Failed to read index file {}", .0.display())]
+    ReadIndex(std::path::PathBuf, #[source] std::io::Error),
+    #[error("Rate limited by the registry")]
+    RateLimited {
+        /// How long the registry asked us to wait, from the `Retry-After` header
+        retry_after: Option<Duration>,
+    },
+    #[error("No cargo index cache found in {}, run cargo fetch first", .0.display())]
+    NoCargoCache(std::path::PathBuf),
+    #[error("Crate {0} is not in the cargo index cache, run cargo fetch or leave out --offline")]
+    NotInCargoCache(String),
+    #[error("Unsupported format of cargo index cache file {}", .0.display())]
+    CacheFormat(std::path::PathBuf),
+    #[error("Invalid user agent {0:?}")]
+    UserAgent(String),
+    #[error("Invalid version {0:?}")]
+    InvalidVersion(String, #[source] semver::Error),
+    #[error(
+        "Cargo.lock format version {0} is not supported yet, the newest supported version is 4"
+    )]
+    UnsupportedLockfileVersion(u32),
+    #[error("Invalid checksum {0:?}")]
+    InvalidChecksum(String, #[source] cargo_lock::Error),
+    #[error("Failed to run cargo update")]
+    CargoUpdate(#[source] std::io::Error),
+    #[error("Failed to parse Cargo.toml")]
+    ParseManifest(#[source] toml::de::Error),
     #[error("At least for one crate there was no appropriate version found")]
     NoAppropriateVersion,
+    #[error("Interrupted")]
+    Interrupted,
+    #[error("Failed to read cargo config {}", .0.display())]
+    ReadCargoConfig(std::path::PathBuf, #[source] std::io::Error),
+    #[error("Failed to parse cargo config {}", .0.display())]
+    ParseCargoConfig(std::path::PathBuf, #[source] Box<toml::de::Error>),
+    #[error("Source {0:?} that replaces crates.io is not defined in the cargo config")]
+    UnknownSource(String),
+    #[error(
+        "The sources that replace crates.io in the cargo config replace each other in a cycle at {0:?}"
+    )]
+    SourceReplacementCycle(String),
+    #[error("Replacing crates.io with {0} is not supported, pass --index or --api-base instead")]
+    UnsupportedSource(String),
 }
 type Result<T> = std::result::Result<T, Error>;
 
-/// Get all crate names of transitive dependencies from in Cargo.lock file up to `dependency_level`
-pub fn get_dependencies(
+impl Error {
+    /// Whether the error is likely to go away when trying again, i.e. a timeout, a failed or reset
+    /// connection, a server error or rate limiting. An unreachable proxy or an invalid request is not retried.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Reqwest(err) if err.is_builder() => false,
+            Error::Reqwest(err) => {
+                err.is_timeout()
+                    || err.is_connect()
+                    || is_connection_reset(err)
+                    || err.status().is_some_and(|status| {
+                        status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    })
+            }
+            Error::RateLimited { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Whether the connection was closed by the other side while the request was sent or the response read
+fn is_connection_reset(err: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            return matches!(
+                err.kind(),
+                std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted
+            );
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// A crate to downgrade together with the package it is locked to
+#[derive(Debug, Clone, Copy)]
+pub struct Dependency<'a> {
+    pub name: &'a str,
+    /// The package in the lockfile, `None` if the crate is not in the lockfile
+    pub locked: Option<&'a cargo_lock::Package>,
+}
+
+impl<'a> Dependency<'a> {
+    /// Look up crate `name` in `lockfile`
+    pub fn from_lockfile(lockfile: &'a Lockfile, name: &'a str) -> Self {
+        let locked = lockfile
+            .packages
+            .iter()
+            .find(|package| package.name.as_str() == name);
+        Dependency { name, locked }
+    }
+
+    /// Look up all versions of crate `name` in `lockfile`, e.g. both `rand 0.7` and `rand 0.8`
+    pub fn all_from_lockfile(lockfile: &'a Lockfile, name: &'a str) -> Vec<Self> {
+        let dependencies: Vec<_> = lockfile
+            .packages
+            .iter()
+            .filter(|package| package.name.as_str() == name)
+            .map(|package| Dependency {
+                name,
+                locked: Some(package),
+            })
+            .collect();
+        if dependencies.is_empty() {
+            vec![Dependency { name, locked: None }]
+        } else {
+            dependencies
+        }
+    }
+
+    /// The source the crate is locked from
+    pub fn source(&self) -> Option<&'a SourceId> {
+        self.locked.and_then(|package| package.source.as_ref())
+    }
+
+    /// Whether the crate comes from a registry rather than from a path or git.
+    /// Crates that are not in the lockfile are assumed to come from crates.io.
+    pub fn is_from_registry(&self) -> bool {
+        match self.locked {
+            Some(package) => package
+                .source
+                .as_ref()
+                .is_some_and(|source| source.is_registry()),
+            None => true,
+        }
+    }
+
+    /// Whether the crate is locked from the registry with the index at `index_url`,
+    /// e.g. "https://github.com/rust-lang/crates.io-index"
+    pub fn is_from_source(&self, index_url: &str) -> bool {
+        self.source().is_some_and(|source| {
+            source.url().as_str().trim_end_matches('/') == index_url.trim_end_matches('/')
+        })
+    }
+}
+
+/// Whether `package` is locked from git or a path instead of a registry, as crates overridden in a `[patch]`
+/// section are. Pinning them to a registry version would have no effect.
+fn is_patched(package: &cargo_lock::Package) -> bool {
+    package
+        .source
+        .as_ref()
+        .is_some_and(|source| !source.is_registry())
+}
+
+/// Get the crate names of the direct dependencies of all local crates such as workspace members
+pub fn get_direct_dependencies<'t>(
+    dependency_tree: &'t cargo_lock::dependency::Tree,
+    kinds: Option<&KindFilter>,
+) -> HashSet<&'t str> {
+    let graph = dependency_tree.graph();
+    graph
+        .node_indices()
+        .filter(|&node_index| graph[node_index].source.is_none())
+        .flat_map(|node_index| {
+            let package = &graph[node_index];
+            graph
+                .neighbors_directed(node_index, petgraph::Direction::Outgoing)
+                .map(|child| &graph[child])
+                .filter(move |dependency| {
+                    dependency.source.is_some()
+                        && !is_patched(dependency)
+                        && kinds.is_none_or(|kinds| kinds.follows(package, dependency))
+                })
+        })
+        .map(|dependency| dependency.name.as_str())
+        .collect()
+}
+
+/// Kind of a dependency, i.e. whether it is listed in `[dependencies]`, `[dev-dependencies]` or `[build-dependencies]`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DependencyKind {
+    Normal,
+    Dev,
+    Build,
+}
+
+/// Restricts which dependency edges are followed when walking the dependency tree.
+///
+/// Cargo.lock does not record the kind of a dependency, so the kinds are only known for the dependencies of
+/// workspace members, e.g. from `cargo metadata`. All other edges are always followed.
+#[derive(Debug, Default)]
+pub struct KindFilter {
+    /// Kinds of dependencies that are followed
+    pub kinds: HashSet<DependencyKind>,
+    /// Kinds of the dependencies of the workspace members, keyed by member and dependency name
+    pub workspace: HashMap<(String, String), HashSet<DependencyKind>>,
+}
+
+impl KindFilter {
+    /// Whether the edge from `package` to its dependency `dependency` is followed
+    fn follows(&self, package: &cargo_lock::Package, dependency: &cargo_lock::Package) -> bool {
+        if package.source.is_some() {
+            return true;
+        }
+        let key = (package.name.to_string(), dependency.name.to_string());
+        match self.workspace.get(&key) {
+            Some(kinds) => !kinds.is_disjoint(&self.kinds),
+            None => true,
+        }
+    }
+}
+
+/// Get the crate names of transitive dependencies in Cargo.lock on exactly `dependency_level`, or on all
+/// levels if it is `None`. Every crate counts on the shallowest level it is found on only.
+/// Local crates such as workspace members are left out, as are dependencies not reached through `kinds`.
+/// With `include_roots`, the root crates on level 0 that nothing depends on are included as well, unless
+/// they are local.
+pub fn get_dependencies<'t>(
     dependency_level: Option<NonZeroU8>,
-    dependency_tree: &cargo_lock::dependency::Tree,
-) -> HashSet<&str> {
-    let mut crate_names = HashSet::new();
+    dependency_tree: &'t cargo_lock::dependency::Tree,
+    kinds: Option<&KindFilter>,
+    include_roots: bool,
+) -> HashSet<&'t str> {
+    get_dependency_levels(dependency_level, dependency_tree, kinds, include_roots)
+        .into_keys()
+        .collect()
+}
+
+/// Like [`get_dependencies`], but together with the shallowest level each crate has been found on
+pub fn get_dependency_levels<'t>(
+    dependency_level: Option<NonZeroU8>,
+    dependency_tree: &'t cargo_lock::dependency::Tree,
+    kinds: Option<&KindFilter>,
+    include_roots: bool,
+) -> HashMap<&'t str, u8> {
+    let mut crate_names = HashMap::new();
 
     // initialize the worklist with the root nodes
     let mut worklist: Vec<petgraph::prelude::NodeIndex> = dependency_tree
         .graph()
         .externals(petgraph::Direction::Incoming)
         .collect();
+    // every crate is only processed once, on the shallowest level it is reachable on
+    let mut visited: HashSet<petgraph::prelude::NodeIndex> = worklist.iter().copied().collect();
 
     let mut level: u8 = 0;
     while !worklist.is_empty() {
@@ -53,13 +484,25 @@ pub fn get_dependencies(
         // iterate all dependencies on the current level
         for node_index in worklist {
             let package: &cargo_lock::Package = &dependency_tree.graph()[node_index];
-            dependencies_current_level.insert(package.name.as_str());
+            // local crates such as workspace members have no source and are never downgraded
+            if is_patched(package) {
+                info!(
+                    "crate {} is patched or a git dependency, skipped",
+                    package.name
+                );
+            } else if package.source.is_some() {
+                dependencies_current_level.insert(package.name.as_str());
+            }
             // push the transitive dependencies on the next level to the worklist
             for child in dependency_tree
                 .graph()
                 .neighbors_directed(node_index, petgraph::Direction::Outgoing)
             {
-                next_level_worklist.push(child);
+                if kinds.is_none_or(|kinds| kinds.follows(package, &dependency_tree.graph()[child]))
+                    && visited.insert(child)
+                {
+                    next_level_worklist.push(child);
+                }
             }
         }
         info!(
@@ -74,15 +517,28 @@ pub fn get_dependencies(
 
         worklist = next_level_worklist;
 
-        if level > 0 {
+        if level == 0 {
+            if include_roots {
+                crate_names.extend(dependencies_current_level.into_iter().map(|name| (name, 0)));
+            }
+        } else {
             match dependency_level {
                 Some(dependency_level) => {
                     if level >= dependency_level.get() {
-                        return dependencies_current_level;
+                        crate_names.extend(
+                            dependencies_current_level
+                                .into_iter()
+                                .map(|name| (name, level)),
+                        );
+                        return crate_names;
                     }
                 }
 
-                None => crate_names.extend(dependencies_current_level),
+                None => {
+                    for name in dependencies_current_level {
+                        crate_names.entry(name).or_insert(level);
+                    }
+                }
             }
         }
 
@@ -98,67 +554,677 @@ pub fn get_dependencies(
     crate_names
 }
 
-fn find_appropriate_version(
+/// Get the crate names of the dependencies that are only reachable through dev-dependencies of the workspace
+/// members, whose kinds are given in `workspace` like in [`KindFilter::workspace`]. Crates that are also
+/// reachable through a normal or build dependency are left out.
+pub fn get_dev_only_dependencies<'t>(
+    dependency_tree: &'t cargo_lock::dependency::Tree,
+    workspace: &HashMap<(String, String), HashSet<DependencyKind>>,
+) -> HashSet<&'t str> {
+    let without_dev = KindFilter {
+        kinds: HashSet::from([DependencyKind::Normal, DependencyKind::Build]),
+        workspace: workspace.clone(),
+    };
+    let reachable = get_dependencies(None, dependency_tree, Some(&without_dev), false);
+    get_dependencies(None, dependency_tree, None, false)
+        .into_iter()
+        .filter(|name| !reachable.contains(name))
+        .collect()
+}
+
+/// Which of the versions published before the date is selected
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// The newest version published before the date
+    #[default]
+    Newest,
+    /// The oldest version published before the date
+    Oldest,
+}
+
+/// Options controlling which version of a crate is selected
+#[derive(Debug, Clone, Default)]
+pub struct SelectOptions {
+    /// Whether the newest or oldest candidate is selected
+    pub strategy: Strategy,
+    /// Also consider yanked versions
+    pub include_yanked: bool,
+    /// Also consider versions published exactly at the target date
+    pub inclusive: bool,
+    /// Only consider versions that are semver compatible with the locked version
+    pub same_major: bool,
+    /// Minimum versions of specific crates, even if they have been published after the date. The names
+    /// may differ from the crate names in `-` and `_`.
+    pub floors: HashMap<String, semver::Version>,
+    /// Dates that specific crates are downgraded to instead of the date given for all crates. The names
+    /// may differ from the crate names in `-` and `_`.
+    pub crate_dates: HashMap<String, DateTime<Utc>>,
+    /// Only consider versions whose `rust-version` is at most this, versions without one are always considered
+    pub msrv: Option<semver::Version>,
+    /// Also consider prerelease versions such as `1.0.0-beta.3`
+    pub allow_prerelease: bool,
+    /// Leave crates whose locked version has been published before the date as they are, instead of
+    /// selecting a version for them, which might be newer than the locked one
+    pub skip_old: bool,
+}
+
+/// Parse a `rust-version` such as "1.56" or "1.56.1", in which the minor and patch version are optional
+pub fn parse_rust_version(rust_version: &str) -> Option<semver::Version> {
+    let mut parts = rust_version.trim().split('.').map(str::parse::<u64>);
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().transpose().ok()?.unwrap_or(0);
+    let patch = parts.next().transpose().ok()?.unwrap_or(0);
+    match parts.next() {
+        Some(_) => None,
+        None => Some(semver::Version::new(major, minor, patch)),
+    }
+}
+
+/// The part of `version` that has to stay the same for semver compatibility,
+/// i.e. the major version or the first non-zero component for `0.x` versions
+fn compatibility_key(version: &semver::Version) -> (u64, u64, u64) {
+    match (version.major, version.minor) {
+        (0, 0) => (0, 0, version.patch),
+        (0, minor) => (0, minor, 0),
+        (major, _) => (major, 0, 0),
+    }
+}
+
+/// Why no version of a crate could be selected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectError {
+    /// No version has been published before the date, with the first version that has been published
+    NoVersionBeforeDate {
+        date: DateTime<Utc>,
+        first: Option<(String, DateTime<Utc>)>,
+    },
+    /// There are versions before the date, but none of them is compatible with the locked version
+    Incompatible { locked: Option<semver::Version> },
+    /// No version before the date is at least the floor, and the floor itself is not available
+    BelowFloor { floor: semver::Version },
+    /// All versions before the date need a newer Rust version than the MSRV
+    RustVersion { msrv: semver::Version },
+    /// There are only prerelease versions before the date, and prereleases are not allowed
+    OnlyPrereleases,
+}
+
+impl fmt::Display for SelectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectError::NoVersionBeforeDate { date, first } => {
+                write!(f, "no version before {}", date.format("%Y-%m-%d"))?;
+                match first {
+                    Some((num, published)) => write!(
+                        f,
+                        " (first published {} as {})",
+                        published.format("%Y-%m-%d"),
+                        num
+                    ),
+                    None => write!(f, " (no unyanked versions at all)"),
+                }
+            }
+            SelectError::Incompatible { locked } => write!(
+                f,
+                "no version before date compatible with the locked version {}",
+                locked.as_ref().map(ToString::to_string).unwrap_or_default()
+            ),
+            SelectError::OnlyPrereleases => write!(
+                f,
+                "only prerelease versions before date, pass --allow-prerelease to consider them"
+            ),
+            SelectError::RustVersion { msrv } => {
+                write!(f, "no version before date that supports Rust {}", msrv)
+            }
+            SelectError::BelowFloor { floor } => write!(
+                f,
+                "no version before date that is at least {floor}, and {floor} itself is not available",
+                floor = floor
+            ),
+        }
+    }
+}
+
+/// Select the version of `crate_name` to downgrade to from the already fetched `versions`, i.e. the newest
+/// (or oldest) one published before `date` that satisfies `options`. `locked` is the version the crate is
+/// currently locked to, which `options.same_major` compares against.
+pub fn find_appropriate_version(
     crate_name: &str,
-    mut versions: Vec<Version>,
+    mut versions: Vec<PublishedVersion>,
+    locked: Option<&semver::Version>,
     date: DateTime<Utc>,
-) -> std::result::Result<Package, String> {
-    // sort versions by release date
-    versions.sort_unstable_by_key(|version| version.updated_at);
+    options: &SelectOptions,
+) -> std::result::Result<Package, SelectError> {
+    // sort versions by release date, `updated_at` also changes when a version is yanked
+    versions.sort_unstable_by_key(|version| version.created_at);
 
-    // find the last version that has been published before `date`
-    match versions
-        .iter()
-        .rev()
-        .find(|version| version.updated_at < date && !version.yanked)
-    {
-        Some(version) => Ok(Package {
-            version: version.num.clone(),
-            name: (*crate_name).to_owned(),
+    let published_before = |version: &PublishedVersion| {
+        version.created_at < date || (options.inclusive && version.created_at == date)
+    };
+    let eligible = |version: &PublishedVersion| {
+        published_before(version) && (options.include_yanked || !version.yanked)
+    };
+    let compatible = |version: &PublishedVersion| match locked {
+        Some(locked) if options.same_major => semver::Version::parse(&version.num)
+            .is_ok_and(|version| compatibility_key(&version) == compatibility_key(locked)),
+        _ => true,
+    };
+    let floor = get_by_crate_name(&options.floors, crate_name);
+    let above_floor = |version: &PublishedVersion| match floor {
+        Some(floor) => semver::Version::parse(&version.num).is_ok_and(|version| version >= *floor),
+        None => true,
+    };
+    let supported = |version: &PublishedVersion| match (&options.msrv, &version.rust_version) {
+        (Some(msrv), Some(rust_version)) => {
+            parse_rust_version(rust_version).is_none_or(|rust_version| rust_version <= *msrv)
+        }
+        _ => true,
+    };
+    // versions that do not parse as semver are kept, cargo would not have locked them otherwise
+    let stable = |version: &PublishedVersion| {
+        options.allow_prerelease
+            || semver::Version::parse(&version.num).map_or(true, |version| version.pre.is_empty())
+    };
+    let mut candidates = versions.iter().filter(|version| {
+        eligible(version)
+            && compatible(version)
+            && stable(version)
+            && above_floor(version)
+            && supported(version)
+    });
+    // find the last (or first) version that has been published before `date`
+    let selected = match options.strategy {
+        Strategy::Newest => candidates.next_back(),
+        Strategy::Oldest => candidates.next(),
+    };
+    // if nothing before the date satisfies the floor, fall back to the floor version itself
+    let selected = selected.or_else(|| {
+        let floor = floor?;
+        let version = versions.iter().find(|version| {
+            (options.include_yanked || !version.yanked)
+                && compatible(version)
+                && semver::Version::parse(&version.num).is_ok_and(|version| version == *floor)
+        })?;
+        warn!(
+            "no version of crate {} of at least {} found before date, using {}",
+            crate_name, floor, version.num
+        );
+        Some(version)
+    });
+    match selected {
+        Some(version) => {
+            if version.yanked {
+                warn!(
+                    "selected yanked version {} of crate {}",
+                    version.num, crate_name
+                );
+            }
+            Ok(Package {
+                version: version.num.clone(),
+                name: (*crate_name).to_owned(),
+                locked: locked.map(ToString::to_string),
+                checksum: version.checksum.clone(),
+            })
+        }
+        None => Err(match (floor, &options.msrv) {
+            (Some(floor), _) => SelectError::BelowFloor {
+                floor: floor.clone(),
+            },
+            (None, _)
+                if !versions
+                    .iter()
+                    .any(|version| eligible(version) && stable(version))
+                    && versions.iter().any(eligible) =>
+            {
+                SelectError::OnlyPrereleases
+            }
+            (None, Some(msrv))
+                if versions
+                    .iter()
+                    .any(|version| eligible(version) && compatible(version) && stable(version)) =>
+            {
+                SelectError::RustVersion { msrv: msrv.clone() }
+            }
+            (None, _) if versions.iter().any(eligible) => SelectError::Incompatible {
+                locked: locked.cloned(),
+            },
+            (None, _) => SelectError::NoVersionBeforeDate {
+                date,
+                first: versions
+                    .iter()
+                    .find(|version| !version.yanked)
+                    .map(|version| (version.num.clone(), version.created_at)),
+            },
         }),
-        None => Err(format!(
-            "No version of crate {} found before date. Oldest unyanked version is: {}",
-            (*crate_name).to_owned(),
-            versions
-                .iter()
-                .find(|version| !version.yanked)
-                .map(|v| format!("{} ({})", v.num, v.updated_at.format("%Y-%m-%d")))
-                .unwrap_or_else(|| "no known versions at all?".to_owned()),
-        )),
     }
 }
 
-/// For every defined package in `cargo_lock`, find the version that has been published before `date`
+/// User-Agent that identifies us to crates.io as required by its crawler policy
+pub const DEFAULT_USER_AGENT: &str = concat!(
+    "cargo-downgrade/",
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("CARGO_PKG_REPOSITORY"),
+    ")"
+);
+
+/// Options controlling how version metadata is fetched
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    /// Cache to read version metadata from and to write fetched metadata to
+    pub cache: Option<Cache>,
+    /// Maximum number of crates whose metadata is fetched concurrently
+    pub jobs: NonZeroUsize,
+    /// Base URL of the web API of the registry that crates from other registries than crates.io are looked up in
+    pub registry: Option<url::Url>,
+    /// Local sparse index that crates.io crates are looked up in instead of the crates.io API
+    pub index: Option<std::path::PathBuf>,
+    /// User-Agent sent with every request to a registry
+    pub user_agent: String,
+    /// Minimum interval between two requests to the same registry
+    pub rate_limit: Duration,
+    /// Time after which a request to a registry is given up and retried like other transient errors
+    pub timeout: Duration,
+    /// How often a request that failed with a transient error is retried
+    pub retries: u32,
+    /// Show a progress bar on stderr while fetching
+    pub progress: bool,
+    /// Look up crates.io crates in cargo's local index cache instead of fetching them
+    pub offline: bool,
+    /// Proxy that requests to registries go through instead of the one in the environment
+    pub proxy: Option<url::Url>,
+    /// Base URL of a crates.io API mirror that crates.io crates are looked up in instead of crates.io itself
+    pub api_base: Option<url::Url>,
+    /// Directory of the project whose cargo config may replace crates.io with another source, see
+    /// [`crates_io_replacement`]. Ignored if `index`, `offline` or `api_base` is set.
+    pub cargo_config_dir: Option<std::path::PathBuf>,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        FetchOptions {
+            cache: None,
+            jobs: NonZeroUsize::new(4).unwrap(),
+            registry: None,
+            index: None,
+            user_agent: DEFAULT_USER_AGENT.to_owned(),
+            rate_limit: Duration::from_millis(1000),
+            timeout: Duration::from_secs(30),
+            retries: 2,
+            progress: false,
+            offline: false,
+            proxy: None,
+            api_base: None,
+            cargo_config_dir: None,
+        }
+    }
+}
+
+/// Network activity of [`get_downgraded_dependencies`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FetchStats {
+    /// Requests to a registry or reads of an index, including the retried ones
+    pub requests: usize,
+    /// Requests that have been retried after a transient error or rate limiting
+    pub retries: usize,
+    /// Crates whose versions have been loaded from the cache instead
+    pub cache_hits: usize,
+    /// Wall time spent fetching
+    pub elapsed: Duration,
+}
+
+/// Counters of [`FetchStats`] shared by the concurrent lookups
+#[derive(Default)]
+struct StatCounters {
+    requests: AtomicUsize,
+    retries: AtomicUsize,
+    cache_hits: AtomicUsize,
+}
+
+async fn fetch_versions(
+    source: &dyn VersionSource,
+    options: &FetchOptions,
+    crate_name: &str,
+    counters: &StatCounters,
+) -> Result<Vec<PublishedVersion>> {
+    let cache = options.cache.as_ref().zip(source.cache_key(crate_name));
+    if let Some(versions) = cache
+        .as_ref()
+        .and_then(|(cache, cache_key)| cache.load(cache_key))
+    {
+        counters.cache_hits.fetch_add(1, Ordering::Relaxed);
+        return Ok(versions);
+    }
+
+    debug!("fetching infos for crate {} from {}", crate_name, source);
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+    let versions = loop {
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        match source.versions(crate_name).await {
+            Ok(versions) => break versions,
+            Err(Error::RateLimited { retry_after }) if attempt < options.retries => {
+                attempt += 1;
+                counters.retries.fetch_add(1, Ordering::Relaxed);
+                let wait = retry_after.unwrap_or(delay);
+                warn!(
+                    "rate limited by {} while fetching infos for crate {}, retrying in {}s ({}/{})",
+                    source,
+                    crate_name,
+                    wait.as_secs(),
+                    attempt,
+                    options.retries
+                );
+                tokio::time::sleep(wait).await;
+                delay *= 2;
+            }
+            Err(err) if err.is_transient() && attempt < options.retries => {
+                attempt += 1;
+                counters.retries.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "fetching infos for crate {} failed, retrying in {}s ({}/{}): {}",
+                    crate_name,
+                    delay.as_secs(),
+                    attempt,
+                    options.retries,
+                    error_reporter::Report::new(&err)
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    if let Some((cache, cache_key)) = cache {
+        cache.store(&cache_key, &versions);
+    }
+    Ok(versions)
+}
+
+/// Pick where to look up `dependency`, or `None` if its source is not supported
+fn source_for<'s>(
+    dependency: &Dependency,
+    crates_io: &'s dyn VersionSource,
+    registry: Option<&'s dyn VersionSource>,
+) -> Option<&'s dyn VersionSource> {
+    if !dependency.is_from_registry() {
+        info!(
+            "skipping crate {}, it is not from a registry",
+            dependency.name
+        );
+        return None;
+    }
+
+    match dependency.source() {
+        None => Some(crates_io),
+        Some(source) if source.is_default_registry() => Some(crates_io),
+        Some(source) if source.is_remote_registry() => {
+            if registry.is_none() {
+                info!(
+                    "skipping crate {} from registry {}, pass --registry to look it up",
+                    dependency.name,
+                    source.url()
+                );
+            }
+            registry
+        }
+        Some(source) => {
+            info!(
+                "skipping crate {} from unsupported source {}",
+                dependency.name,
+                source.url()
+            );
+            None
+        }
+    }
+}
+
+/// Outcome of looking up the versions to downgrade to
+#[derive(Debug, Default)]
+pub struct DowngradeReport {
+    /// Crates for which a version has been found
+    pub resolved: Vec<Package>,
+    /// Crates for which no appropriate version has been found, together with the reason
+    pub failed: Vec<(String, SelectError)>,
+    /// Crates that have not been looked up because their source is not supported
+    pub skipped: Vec<String>,
+    /// Crates that do not exist in the registry, e.g. because of a typo in the name
+    pub not_found: Vec<String>,
+    /// Crates left as they are with [`SelectOptions::skip_old`] because their locked version is older than
+    /// the date
+    pub already_old: Vec<String>,
+    /// How many requests have been needed to look up the crates
+    pub stats: FetchStats,
+}
+
+/// For every crate in `dependencies`, find the version that has been published before `date`, or before its
+/// date in `select_options.crate_dates`.
+/// The versions are looked up in crates.io, or the index and registry given in `options`.
 pub async fn get_downgraded_dependencies(
-    crate_names: &[&str],
+    dependencies: &[Dependency<'_>],
     date: DateTime<Utc>,
-) -> Result<Vec<Package>> {
+    select_options: &SelectOptions,
+    options: &FetchOptions,
+) -> Result<DowngradeReport> {
+    let user_agent = &options.user_agent;
+    let rate_limit = options.rate_limit;
+    let timeout = options.timeout;
+    let proxy = options.proxy.as_ref();
+    let crates_io: Box<dyn VersionSource> = match &options.index {
+        Some(index) => Box::new(SparseIndex::new(index.clone())),
+        None if options.offline => Box::new(CargoIndexCache::from_env()?),
+        None if options.api_base.is_some() => Box::new(RegistryClient::new(
+            options.api_base.clone().unwrap(),
+            user_agent,
+            rate_limit,
+            timeout,
+            proxy,
+        )?),
+        None => {
+            let replacement = match &options.cargo_config_dir {
+                Some(dir) => crates_io_replacement(dir)?,
+                None => None,
+            };
+            // a git index or a directory of vendored crates has no publish dates, so crates.io is used instead
+            let unsupported = |source: String| {
+                warn!(
+                    "crates.io is replaced with {} in the cargo config, which is not supported, looking up crates on crates.io instead; pass --index or --api-base to use a mirror",
+                    source
+                );
+            };
+            let replaced: Option<Box<dyn VersionSource>> = match replacement {
+                Some(SourceReplacement::Registry(index)) => {
+                    match RegistryClient::from_index(&index, user_agent, rate_limit, timeout, proxy)
+                        .await
+                    {
+                        Ok(client) => {
+                            info!("crates.io is replaced with {} in the cargo config", index);
+                            Some(Box::new(client))
+                        }
+                        Err(Error::UnsupportedSource(source)) => {
+                            unsupported(source);
+                            None
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+                Some(SourceReplacement::LocalRegistry(path)) => {
+                    info!(
+                        "crates.io is replaced with local registry {} in the cargo config",
+                        path.display()
+                    );
+                    Some(Box::new(SparseIndex::new(path.join("index"))))
+                }
+                Some(SourceReplacement::Directory(path)) => {
+                    unsupported(format!("directory {}", path.display()));
+                    None
+                }
+                None => None,
+            };
+            match replaced {
+                Some(source) => source,
+                None => Box::new(RegistryClient::crates_io(
+                    user_agent, rate_limit, timeout, proxy,
+                )?),
+            }
+        }
+    };
+    let registry = options
+        .registry
+        .clone()
+        .filter(|_| !options.offline)
+        .map(|url| RegistryClient::new(url, user_agent, rate_limit, timeout, proxy))
+        .transpose()?;
+
+    get_downgraded_dependencies_from(
+        dependencies,
+        date,
+        select_options,
+        options,
+        crates_io.as_ref(),
+        registry
+            .as_ref()
+            .map(|registry| registry as &dyn VersionSource),
+    )
+    .await
+}
+
+/// Like [`get_downgraded_dependencies`], but look up crates.io crates in `crates_io` and crates from other
+/// registries in `registry`
+pub async fn get_downgraded_dependencies_from(
+    dependencies: &[Dependency<'_>],
+    date: DateTime<Utc>,
+    select_options: &SelectOptions,
+    options: &FetchOptions,
+    crates_io: &dyn VersionSource,
+    registry: Option<&dyn VersionSource>,
+) -> Result<DowngradeReport> {
     info!(
         "downgrading the following {} dependencies to {}: {}",
-        crate_names.len(),
+        dependencies.len(),
         date,
-        crate_names.join(", ")
+        dependencies
+            .iter()
+            .map(|dependency| dependency.name)
+            .collect::<Vec<_>>()
+            .join(", ")
     );
-    let cratesio_api_client = crates_io_api::AsyncClient::new(
-        "downgrade crawler (https://github.com/obraunsdorf/cargo-downgrade)", // TODO link to github
-        std::time::Duration::from_millis(1000),
-    )
-    .unwrap();
-
-    // sequentially fetch the version information for all packages since we connect to the crates.io API only every second
-    let mut downgraded_dependencies = vec![];
-    for crate_name in crate_names {
-        info!("fetching infos for crate {}", crate_name);
-        let crate_data = cratesio_api_client.get_crate(crate_name).await?;
-        match find_appropriate_version(crate_name, crate_data.versions, date) {
-            Ok(package) => downgraded_dependencies.push(package),
-            Err(err) => {
-                error!("{}", err);
+    // fetch the version information concurrently, the clients themselves make sure that we connect to each registry only every second
+    let mut report = DowngradeReport::default();
+    // a crate that is locked in several versions is only fetched once, every lookup is shared by all of them
+    let mut lookups: Vec<(&str, &dyn VersionSource)> = vec![];
+    let mut lookup_indices: HashMap<(&str, String), usize> = HashMap::new();
+    let mut selections = vec![];
+    for (index, dependency) in dependencies.iter().enumerate() {
+        match source_for(dependency, crates_io, registry) {
+            Some(source) => {
+                let lookup = *lookup_indices
+                    .entry((dependency.name, source.to_string()))
+                    .or_insert_with(|| {
+                        lookups.push((dependency.name, source));
+                        lookups.len() - 1
+                    });
+                selections.push((index, lookup));
             }
+            None => report.skipped.push(dependency.name.to_owned()),
         }
     }
+    let started = Instant::now();
+    let counters = StatCounters::default();
+    // indicatif does not draw the bar when stderr is not a terminal
+    let progress = if options.progress && !lookups.is_empty() {
+        ProgressBar::new(lookups.len() as u64).with_style(
+            ProgressStyle::with_template("[{bar:30}] {pos}/{len} crates")
+                .expect("the template is valid")
+                .progress_chars("= "),
+        )
+    } else {
+        ProgressBar::hidden()
+    };
+    let mut fetched: Vec<_> = stream::iter(lookups.into_iter().enumerate())
+        .map(|(lookup, (crate_name, source))| {
+            let (progress, counters) = (&progress, &counters);
+            async move {
+                let versions = fetch_versions(source, options, crate_name, counters).await;
+                progress.inc(1);
+                (lookup, versions)
+            }
+        })
+        .buffer_unordered(options.jobs.get())
+        .collect()
+        .await;
+    // remove the bar so that it does not mix with the following output
+    progress.finish_and_clear();
+    report.stats = FetchStats {
+        requests: counters.requests.into_inner(),
+        retries: counters.retries.into_inner(),
+        cache_hits: counters.cache_hits.into_inner(),
+        elapsed: started.elapsed(),
+    };
+    // restore the order of `dependencies` so that the output is reproducible
+    fetched.sort_unstable_by_key(|(lookup, _)| *lookup);
+    let mut fetched_versions = Vec::with_capacity(fetched.len());
+    for (_, versions) in fetched {
+        fetched_versions.push(match versions {
+            Err(Error::CrateNotFound(name)) => {
+                report.not_found.push(name);
+                None
+            }
+            versions => Some(versions?),
+        });
+    }
 
-    Ok(downgraded_dependencies)
+    // a crate that is locked in several versions is downgraded once per semver compatible range
+    let mut occurrences: HashMap<&str, usize> = HashMap::new();
+    for dependency in dependencies {
+        *occurrences.entry(dependency.name).or_default() += 1;
+    }
+    let same_major = SelectOptions {
+        same_major: true,
+        ..select_options.clone()
+    };
+
+    for (index, lookup) in selections {
+        let dependency = &dependencies[index];
+        let Some(versions) = &fetched_versions[lookup] else {
+            continue;
+        };
+        let locked = dependency.locked.map(|package| &package.version);
+        let select_options = match occurrences[dependency.name] {
+            1 => select_options,
+            _ => &same_major,
+        };
+        let date = get_by_crate_name(&select_options.crate_dates, dependency.name)
+            .copied()
+            .unwrap_or(date);
+        // the publish date of the locked version is only known from the fetched versions
+        if select_options.skip_old
+            && let Some(locked) = locked
+            && versions.iter().any(|version| {
+                version.num == locked.to_string()
+                    && (version.created_at < date
+                        || (select_options.inclusive && version.created_at == date))
+            })
+        {
+            debug!(
+                "crate {} is locked to {}, which is already old",
+                dependency.name, locked
+            );
+            report.already_old.push(dependency.name.to_owned());
+            continue;
+        }
+        match find_appropriate_version(
+            dependency.name,
+            versions.clone(),
+            locked,
+            date,
+            select_options,
+        ) {
+            Ok(package) => report.resolved.push(package),
+            Err(err) => report.failed.push((dependency.name.to_owned(), err)),
+        }
+    }
+
+    Ok(report)
 }
 
 #[cfg(test)]
@@ -166,14 +1232,937 @@ mod test {
     use super::*;
 
     #[tokio::test]
+    #[ignore = "needs access to crates.io"]
     async fn test_get_downgraded_dependencies() {
         let datetime: DateTime<Utc> = DateTime::parse_from_rfc2822("22 Feb 2021 23:16:09 GMT")
             .unwrap()
             .with_timezone(&Utc);
-        let crate_names = vec!["serde"];
-        let downgraded_dependencies = get_downgraded_dependencies(&crate_names, datetime)
-            .await
-            .unwrap();
-        assert_eq!(downgraded_dependencies[0].version, "1.0.123");
+        let dependencies = vec![Dependency {
+            name: "serde",
+            locked: None,
+        }];
+        let downgraded_dependencies = get_downgraded_dependencies(
+            &dependencies,
+            datetime,
+            &SelectOptions::default(),
+            &FetchOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(downgraded_dependencies.resolved[0].version, "1.0.123");
+    }
+
+    /// Versions of crates kept in memory instead of fetched from a registry, with the number of lookups
+    struct MockSource(HashMap<&'static str, Vec<PublishedVersion>>, AtomicUsize);
+
+    impl fmt::Display for MockSource {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "mock")
+        }
+    }
+
+    impl VersionSource for MockSource {
+        fn versions<'a>(
+            &'a self,
+            crate_name: &'a str,
+        ) -> futures::future::BoxFuture<'a, Result<Vec<PublishedVersion>>> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            let versions = self.0.get(crate_name).cloned().unwrap_or_default();
+            Box::pin(async move { Ok(versions) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_downgraded_dependencies_from_mock() {
+        let source = MockSource(
+            HashMap::from([
+                (
+                    "serde",
+                    vec![
+                        published("1.0.123", "2021-01-26T00:00:00Z"),
+                        published("1.0.124", "2021-03-05T00:00:00Z"),
+                    ],
+                ),
+                ("log", vec![published("0.4.14", "2021-01-27T00:00:00Z")]),
+                ("rand", vec![published("0.8.3", "2021-03-01T00:00:00Z")]),
+            ]),
+            AtomicUsize::new(0),
+        );
+        let date = DateTime::parse_from_rfc3339("2021-02-22T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let dependencies = ["serde", "log", "rand"].map(|name| Dependency { name, locked: None });
+
+        let report = get_downgraded_dependencies_from(
+            &dependencies,
+            date,
+            &SelectOptions::default(),
+            &FetchOptions::default(),
+            &source,
+            None,
+        )
+        .await
+        .unwrap();
+        let resolved: Vec<_> = report
+            .resolved
+            .iter()
+            .map(|package| (package.name.as_str(), package.version.as_str()))
+            .collect();
+        assert_eq!(resolved, [("serde", "1.0.123"), ("log", "0.4.14")]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "rand");
+        assert_eq!(
+            report.failed[0].1.to_string(),
+            "no version before 2021-02-22 (first published 2021-03-01 as 0.8.3)"
+        );
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(Error::RateLimited { retry_after: None }.is_transient());
+        // a request that cannot be built or a proxy that cannot be reached fails the same way again
+        let invalid_proxy = || reqwest::Proxy::all("not a proxy").unwrap_err();
+        assert!(!Error::Reqwest(invalid_proxy()).is_transient());
+        assert!(
+            !Error::Proxy("http://proxy.example:3128".to_owned(), invalid_proxy()).is_transient()
+        );
+        assert!(!Error::CrateNotFound("serde".to_owned()).is_transient());
+    }
+
+    #[tokio::test]
+    async fn test_find_appropriate_version_floor() {
+        let versions = vec![
+            published("1.0.0", "2021-01-01T00:00:00Z"),
+            published("1.1.0", "2021-01-15T00:00:00Z"),
+            published("1.2.0", "2021-02-10T00:00:00Z"),
+            published("1.3.0", "2021-03-01T00:00:00Z"),
+        ];
+        let date = DateTime::parse_from_rfc3339("2021-02-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let floor = |version, strategy| SelectOptions {
+            floors: HashMap::from([("foo".to_owned(), semver::Version::parse(version).unwrap())]),
+            strategy,
+            ..SelectOptions::default()
+        };
+
+        // the oldest version before the date that is at least the floor
+        let package = find_appropriate_version(
+            "foo",
+            versions.clone(),
+            None,
+            date,
+            &floor("1.1.0", Strategy::Oldest),
+        )
+        .unwrap();
+        assert_eq!(package.version, "1.1.0");
+        // the floor itself, although it has been published after the date
+        let package = find_appropriate_version(
+            "foo",
+            versions.clone(),
+            None,
+            date,
+            &floor("1.2.0", Strategy::Newest),
+        )
+        .unwrap();
+        assert_eq!(package.version, "1.2.0");
+        let err = find_appropriate_version(
+            "foo",
+            versions.clone(),
+            None,
+            date,
+            &floor("1.2.5", Strategy::Newest),
+        )
+        .unwrap_err();
+        assert!(matches!(err, SelectError::BelowFloor { floor } if floor.to_string() == "1.2.5"));
+        // - and _ are the same in the names of the floors
+        let package = find_appropriate_version(
+            "foo_bar",
+            versions.clone(),
+            None,
+            date,
+            &SelectOptions {
+                floors: HashMap::from([("foo-bar".to_owned(), semver::Version::new(1, 2, 0))]),
+                ..SelectOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(package.version, "1.2.0");
+
+        // the floors only apply to their crate
+        let source = MockSource(
+            HashMap::from([("foo", versions.clone()), ("bar", versions)]),
+            AtomicUsize::new(0),
+        );
+        let dependencies = ["foo", "bar"].map(|name| Dependency { name, locked: None });
+        let report = get_downgraded_dependencies_from(
+            &dependencies,
+            date,
+            &floor("1.2.5", Strategy::Newest),
+            &FetchOptions::default(),
+            &source,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.resolved.len(), 1);
+        assert_eq!(report.resolved[0].name, "bar");
+        assert_eq!(report.resolved[0].version, "1.1.0");
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "foo");
+        assert!(matches!(report.failed[0].1, SelectError::BelowFloor { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_crate_date_spelling() {
+        let source = MockSource(
+            HashMap::from([(
+                "serde_json",
+                vec![
+                    published("1.0.60", "2021-01-01T00:00:00Z"),
+                    published("1.0.61", "2021-02-01T00:00:00Z"),
+                ],
+            )]),
+            AtomicUsize::new(0),
+        );
+        let date = DateTime::parse_from_rfc3339("2021-03-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let crate_date = DateTime::parse_from_rfc3339("2021-01-15T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let options = SelectOptions {
+            crate_dates: HashMap::from([("serde-json".to_owned(), crate_date)]),
+            ..SelectOptions::default()
+        };
+        let dependencies = [Dependency {
+            name: "serde_json",
+            locked: None,
+        }];
+
+        let report = get_downgraded_dependencies_from(
+            &dependencies,
+            date,
+            &options,
+            &FetchOptions::default(),
+            &source,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.resolved[0].version, "1.0.60");
+    }
+
+    #[tokio::test]
+    async fn test_get_downgraded_dependencies_with_two_majors() {
+        let lockfile: Lockfile = r#"
+version = 3
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = ["rand 0.7.3", "rand 0.8.3"]
+
+[[package]]
+name = "rand"
+version = "0.7.3"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "rand"
+version = "0.8.3"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#
+        .parse()
+        .unwrap();
+        let dependencies = Dependency::all_from_lockfile(&lockfile, "rand");
+        assert_eq!(dependencies.len(), 2);
+
+        let source = MockSource(
+            HashMap::from([(
+                "rand",
+                vec![
+                    published("0.7.2", "2019-09-16T00:00:00Z"),
+                    published("0.7.3", "2020-01-10T00:00:00Z"),
+                    published("0.8.0", "2020-12-18T00:00:00Z"),
+                    published("0.8.3", "2021-01-25T00:00:00Z"),
+                ],
+            )]),
+            AtomicUsize::new(0),
+        );
+        let date = DateTime::parse_from_rfc3339("2021-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let report = get_downgraded_dependencies_from(
+            &dependencies,
+            date,
+            &SelectOptions::default(),
+            &FetchOptions::default(),
+            &source,
+            None,
+        )
+        .await
+        .unwrap();
+        // every version is downgraded on its own, within its semver compatible range
+        let resolved: Vec<_> = report
+            .resolved
+            .iter()
+            .map(|package| (package.locked.as_deref(), package.version.as_str()))
+            .collect();
+        assert_eq!(
+            resolved,
+            [(Some("0.7.3"), "0.7.3"), (Some("0.8.3"), "0.8.0")]
+        );
+        // both versions share one lookup
+        assert_eq!(source.1.load(Ordering::Relaxed), 1);
+        assert_eq!(report.stats.requests, 1);
+        assert_eq!(report.stats.cache_hits, 0);
+
+        // 0.7.3 has been published before the date, only 0.8.3 needs to be downgraded
+        let options = SelectOptions {
+            skip_old: true,
+            ..SelectOptions::default()
+        };
+        let report = get_downgraded_dependencies_from(
+            &dependencies,
+            date,
+            &options,
+            &FetchOptions::default(),
+            &source,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.already_old, ["rand"]);
+        assert_eq!(report.resolved.len(), 1);
+        assert_eq!(report.resolved[0].version, "0.8.0");
+    }
+
+    const WORKSPACE_LOCKFILE: &str = r#"
+version = 3
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = ["helper", "serde"]
+
+[[package]]
+name = "helper"
+version = "0.1.0"
+dependencies = ["log"]
+
+[[package]]
+name = "log"
+version = "0.4.14"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "serde"
+version = "1.0.123"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+
+    #[test]
+    fn test_get_dependencies_skips_patched_crates() {
+        // serde is overridden by a [patch.crates-io] entry pointing to a git repository
+        let lockfile: Lockfile = r#"
+version = 3
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = ["serde"]
+
+[[package]]
+name = "serde"
+version = "1.0.123"
+source = "git+https://github.com/serde-rs/serde?branch=master#0123456789abcdef0123456789abcdef01234567"
+dependencies = ["serde_derive"]
+
+[[package]]
+name = "serde_derive"
+version = "1.0.123"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#
+        .parse()
+        .unwrap();
+        let tree = lockfile.dependency_tree().unwrap();
+
+        // the dependencies of the patched crate are still downgraded
+        let dependencies = get_dependencies(None, &tree, None, false);
+        assert_eq!(dependencies, HashSet::from(["serde_derive"]));
+        assert!(get_direct_dependencies(&tree, None).is_empty());
+    }
+
+    #[test]
+    fn test_get_dependencies_skips_workspace_members() {
+        let lockfile: Lockfile = WORKSPACE_LOCKFILE.parse().unwrap();
+        let tree = lockfile.dependency_tree().unwrap();
+
+        let dependencies = get_dependencies(None, &tree, None, false);
+        assert_eq!(dependencies, HashSet::from(["log", "serde"]));
+
+        let dependencies = get_dependencies(NonZeroU8::new(1), &tree, None, false);
+        assert_eq!(dependencies, HashSet::from(["serde"]));
+
+        // log is a direct dependency of the workspace member helper
+        let dependencies = get_direct_dependencies(&tree, None);
+        assert_eq!(dependencies, HashSet::from(["log", "serde"]));
+    }
+
+    #[test]
+    fn test_get_dependencies_include_roots() {
+        // app and its path dependency helper are local, tool is a root from crates.io
+        let lockfile: Lockfile = r#"
+version = 3
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = ["helper", "serde"]
+
+[[package]]
+name = "helper"
+version = "0.1.0"
+
+[[package]]
+name = "serde"
+version = "1.0.123"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "tool"
+version = "0.2.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#
+        .parse()
+        .unwrap();
+        let tree = lockfile.dependency_tree().unwrap();
+
+        let dependencies = get_dependencies(None, &tree, None, false);
+        assert_eq!(dependencies, HashSet::from(["serde"]));
+        // local crates cannot be looked up in a registry and set with cargo update, even as roots
+        let dependencies = get_dependencies(None, &tree, None, true);
+        assert_eq!(dependencies, HashSet::from(["serde", "tool"]));
+        let dependencies = get_dependencies(NonZeroU8::new(1), &tree, None, true);
+        assert_eq!(dependencies, HashSet::from(["serde", "tool"]));
+    }
+
+    #[test]
+    fn test_get_dependencies_visits_shared_dependencies_once() {
+        let lockfile: Lockfile = r#"
+version = 3
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = ["a", "b", "d"]
+
+[[package]]
+name = "a"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+dependencies = ["c"]
+
+[[package]]
+name = "b"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+dependencies = ["c"]
+
+[[package]]
+name = "c"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+dependencies = ["d"]
+
+[[package]]
+name = "d"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#
+        .parse()
+        .unwrap();
+        let tree = lockfile.dependency_tree().unwrap();
+
+        let dependencies = get_dependencies(None, &tree, None, false);
+        assert_eq!(dependencies, HashSet::from(["a", "b", "c", "d"]));
+        // c is reachable through both a and b, but only counted once on level 2
+        let dependencies = get_dependencies(NonZeroU8::new(2), &tree, None, false);
+        assert_eq!(dependencies, HashSet::from(["c"]));
+        // d has already been processed on level 1, so it is not revisited through c
+        let dependencies = get_dependencies(NonZeroU8::new(3), &tree, None, false);
+        assert!(dependencies.is_empty());
+
+        let levels = get_dependency_levels(None, &tree, None, false);
+        assert_eq!(
+            levels,
+            HashMap::from([("a", 1), ("b", 1), ("c", 2), ("d", 1)])
+        );
+    }
+
+    /// app depends on log and serde, and on criterion, which depends on itoa and serde, as a dev-dependency
+    const DEV_LOCKFILE: &str = r#"
+version = 3
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = ["criterion", "log", "serde"]
+
+[[package]]
+name = "criterion"
+version = "0.3.4"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+dependencies = ["itoa", "serde"]
+
+[[package]]
+name = "itoa"
+version = "0.4.7"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "log"
+version = "0.4.14"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "serde"
+version = "1.0.123"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+
+    /// The kinds of the dependencies of app in [`DEV_LOCKFILE`]
+    fn dev_workspace_kinds() -> HashMap<(String, String), HashSet<DependencyKind>> {
+        let edge = |dependency: &str, kinds: &[DependencyKind]| {
+            (
+                ("app".to_owned(), dependency.to_owned()),
+                HashSet::from_iter(kinds.iter().copied()),
+            )
+        };
+        HashMap::from([
+            edge("criterion", &[DependencyKind::Dev]),
+            edge("log", &[DependencyKind::Normal, DependencyKind::Dev]),
+            edge("serde", &[DependencyKind::Normal]),
+        ])
+    }
+
+    #[test]
+    fn test_get_dependencies_without_dev_dependencies() {
+        let lockfile: Lockfile = DEV_LOCKFILE.parse().unwrap();
+        let tree = lockfile.dependency_tree().unwrap();
+        let filter = KindFilter {
+            kinds: HashSet::from([DependencyKind::Normal, DependencyKind::Build]),
+            workspace: dev_workspace_kinds(),
+        };
+
+        // itoa is only reachable through the dev-dependency criterion, serde also through a normal one
+        let dependencies = get_dependencies(None, &tree, Some(&filter), false);
+        assert_eq!(dependencies, HashSet::from(["log", "serde"]));
+    }
+
+    #[test]
+    fn test_get_dev_only_dependencies() {
+        let lockfile: Lockfile = DEV_LOCKFILE.parse().unwrap();
+        let tree = lockfile.dependency_tree().unwrap();
+
+        // serde is reachable through criterion as well, but also through the normal dependency of app
+        let dependencies = get_dev_only_dependencies(&tree, &dev_workspace_kinds());
+        assert_eq!(dependencies, HashSet::from(["criterion", "itoa"]));
+    }
+
+    #[test]
+    fn test_to_dot() {
+        // the names of the nodes and the edges between them by name
+        fn parse(dot: &str) -> (HashSet<String>, HashSet<(String, String)>) {
+            let mut labels = HashMap::new();
+            let mut edges = vec![];
+            for line in dot.lines().map(str::trim) {
+                if let Some((index, label)) = line.split_once(" [label=\"") {
+                    let name = label.split(' ').next().unwrap();
+                    labels.insert(index.to_owned(), name.to_owned());
+                } else if let Some((from, to)) = line.split_once(" -> ") {
+                    edges.push((from.to_owned(), to.trim_end_matches(';').to_owned()));
+                }
+            }
+            let edges = edges
+                .into_iter()
+                .map(|(from, to)| (labels[&from].clone(), labels[&to].clone()))
+                .collect();
+            (labels.into_values().collect(), edges)
+        }
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let edge = |from: &str, to: &str| (from.to_owned(), to.to_owned());
+
+        // the local crates app and helper are always shown, log is on level 2
+        let lockfile: Lockfile = WORKSPACE_LOCKFILE.parse().unwrap();
+        let tree = lockfile.dependency_tree().unwrap();
+        let dependencies = get_dependencies(NonZeroU8::new(1), &tree, None, false);
+        let (nodes, edges) = parse(&to_dot(&tree, &dependencies));
+        assert_eq!(nodes, names(&["app", "helper", "serde"]));
+        assert_eq!(
+            edges,
+            HashSet::from([edge("app", "helper"), edge("app", "serde")])
+        );
+
+        // itoa is only reachable through the dev-dependency criterion
+        let lockfile: Lockfile = DEV_LOCKFILE.parse().unwrap();
+        let tree = lockfile.dependency_tree().unwrap();
+        let filter = KindFilter {
+            kinds: HashSet::from([DependencyKind::Normal, DependencyKind::Build]),
+            workspace: dev_workspace_kinds(),
+        };
+        let dependencies = get_dependencies(None, &tree, Some(&filter), false);
+        let (nodes, edges) = parse(&to_dot(&tree, &dependencies));
+        assert_eq!(nodes, names(&["app", "log", "serde"]));
+        assert_eq!(
+            edges,
+            HashSet::from([edge("app", "log"), edge("app", "serde")])
+        );
+    }
+
+    fn published(num: &str, date: &str) -> PublishedVersion {
+        let date = DateTime::parse_from_rfc3339(date)
+            .unwrap()
+            .with_timezone(&Utc);
+        PublishedVersion {
+            num: num.to_owned(),
+            created_at: date,
+            updated_at: date,
+            yanked: false,
+            rust_version: None,
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn test_find_appropriate_version() {
+        let versions = vec![
+            published("1.2.0", "2021-03-01T00:00:00Z"),
+            published("1.0.0", "2021-01-01T00:00:00Z"),
+            published("1.1.0", "2021-02-01T00:00:00Z"),
+        ];
+        let date = DateTime::parse_from_rfc3339("2021-02-22T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let package =
+            find_appropriate_version("foo", versions, None, date, &SelectOptions::default())
+                .unwrap();
+        assert_eq!(package.name, "foo");
+        assert_eq!(package.version, "1.1.0");
+    }
+
+    #[test]
+    fn test_find_appropriate_version_none_before_date() {
+        let versions = vec![published("1.0.0", "2021-03-01T00:00:00Z")];
+        let date = DateTime::parse_from_rfc3339("2021-02-22T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let err = find_appropriate_version("foo", versions, None, date, &SelectOptions::default())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            SelectError::NoVersionBeforeDate {
+                date,
+                first: Some((
+                    "1.0.0".to_owned(),
+                    DateTime::parse_from_rfc3339("2021-03-01T00:00:00Z")
+                        .unwrap()
+                        .with_timezone(&Utc)
+                )),
+            }
+        );
+    }
+
+    #[test]
+    fn test_find_appropriate_version_all_yanked() {
+        let yanked = |num, date| PublishedVersion {
+            yanked: true,
+            ..published(num, date)
+        };
+        let versions = vec![
+            yanked("1.0.0", "2021-01-01T00:00:00Z"),
+            yanked("1.1.0", "2021-02-01T00:00:00Z"),
+        ];
+        let date = DateTime::parse_from_rfc3339("2021-02-22T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let err = find_appropriate_version(
+            "foo",
+            versions.clone(),
+            None,
+            date,
+            &SelectOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err, SelectError::NoVersionBeforeDate { date, first: None });
+
+        let include_yanked = SelectOptions {
+            include_yanked: true,
+            ..SelectOptions::default()
+        };
+        let package =
+            find_appropriate_version("foo", versions, None, date, &include_yanked).unwrap();
+        assert_eq!(package.version, "1.1.0");
+    }
+
+    #[test]
+    fn test_find_appropriate_version_at_boundary() {
+        let versions = vec![
+            published("1.0.0", "2021-02-21T00:00:00Z"),
+            published("1.1.0", "2021-02-22T00:00:00Z"),
+        ];
+        let date = DateTime::parse_from_rfc3339("2021-02-22T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let exclusive = SelectOptions::default();
+        let package =
+            find_appropriate_version("foo", versions.clone(), None, date, &exclusive).unwrap();
+        assert_eq!(package.version, "1.0.0");
+
+        let inclusive = SelectOptions {
+            inclusive: true,
+            ..SelectOptions::default()
+        };
+        let package = find_appropriate_version("foo", versions, None, date, &inclusive).unwrap();
+        assert_eq!(package.version, "1.1.0");
+    }
+
+    #[test]
+    fn test_find_appropriate_version_ignores_later_yank_activity() {
+        // 1.0.1 was published before the date but yanked and un-yanked afterwards
+        let mut yanked_later = published("1.0.1", "2021-02-10T00:00:00Z");
+        yanked_later.updated_at = DateTime::parse_from_rfc3339("2021-06-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let versions = vec![published("1.0.0", "2021-02-01T00:00:00Z"), yanked_later];
+        let date = DateTime::parse_from_rfc3339("2021-02-22T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let package =
+            find_appropriate_version("foo", versions, None, date, &SelectOptions::default())
+                .unwrap();
+        assert_eq!(package.version, "1.0.1");
+    }
+
+    #[test]
+    fn test_find_appropriate_version_msrv() {
+        let with_rust_version = |num, date, rust_version: Option<&str>| PublishedVersion {
+            rust_version: rust_version.map(str::to_owned),
+            ..published(num, date)
+        };
+        let versions = vec![
+            with_rust_version("1.0.0", "2021-01-01T00:00:00Z", None),
+            with_rust_version("1.1.0", "2021-01-02T00:00:00Z", Some("1.56")),
+            with_rust_version("1.2.0", "2021-01-03T00:00:00Z", Some("1.60.0")),
+        ];
+        let date = DateTime::parse_from_rfc3339("2021-02-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let msrv = |msrv| SelectOptions {
+            msrv: parse_rust_version(msrv),
+            ..SelectOptions::default()
+        };
+
+        let package =
+            find_appropriate_version("foo", versions.clone(), None, date, &msrv("1.59")).unwrap();
+        assert_eq!(package.version, "1.1.0");
+        let package =
+            find_appropriate_version("foo", versions.clone(), None, date, &msrv("1.56")).unwrap();
+        assert_eq!(package.version, "1.1.0");
+        // versions without a rust-version are assumed to work with any toolchain
+        let package = find_appropriate_version("foo", versions, None, date, &msrv("1.40")).unwrap();
+        assert_eq!(package.version, "1.0.0");
+    }
+
+    #[test]
+    fn test_find_appropriate_version_prerelease() {
+        let versions = vec![
+            published("0.9.0", "2021-01-01T00:00:00Z"),
+            published("1.0.0-beta.3", "2021-02-01T00:00:00Z"),
+            published("1.0.0", "2021-03-01T00:00:00Z"),
+        ];
+        let date = DateTime::parse_from_rfc3339("2021-02-22T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let allow_prerelease = SelectOptions {
+            allow_prerelease: true,
+            ..SelectOptions::default()
+        };
+
+        let stable = find_appropriate_version(
+            "foo",
+            versions.clone(),
+            None,
+            date,
+            &SelectOptions::default(),
+        );
+        assert_eq!(stable.unwrap().version, "0.9.0");
+        let prerelease =
+            find_appropriate_version("foo", versions.clone(), None, date, &allow_prerelease);
+        assert_eq!(prerelease.unwrap().version, "1.0.0-beta.3");
+
+        // a crate that only had prereleases before the date
+        let only_prereleases = find_appropriate_version(
+            "foo",
+            versions[1..].to_vec(),
+            None,
+            date,
+            &SelectOptions::default(),
+        );
+        assert_eq!(only_prereleases.unwrap_err(), SelectError::OnlyPrereleases);
+    }
+
+    #[test]
+    fn test_find_appropriate_version_oldest() {
+        let versions = vec![
+            published("1.0.0", "2021-01-01T00:00:00Z"),
+            published("1.1.0", "2021-02-01T00:00:00Z"),
+            published("1.2.0", "2021-03-01T00:00:00Z"),
+        ];
+        let date = DateTime::parse_from_rfc3339("2021-02-22T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let newest = find_appropriate_version(
+            "foo",
+            versions.clone(),
+            None,
+            date,
+            &SelectOptions::default(),
+        );
+        assert_eq!(newest.unwrap().version, "1.1.0");
+
+        let oldest = SelectOptions {
+            strategy: Strategy::Oldest,
+            ..SelectOptions::default()
+        };
+        let oldest = find_appropriate_version("foo", versions, None, date, &oldest);
+        assert_eq!(oldest.unwrap().version, "1.0.0");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("tokio*", "tokio"));
+        assert!(glob_match("tokio*", "tokio-util"));
+        assert!(glob_match("*serde*", "erased-serde"));
+        assert!(glob_match("rand_?hacha", "rand_chacha"));
+        assert!(glob_match("serde", "serde"));
+        assert!(!glob_match("serde", "serde_json"));
+        assert!(!glob_match("tokio*", "mio"));
+        assert!(!glob_match("*-sys", "libc"));
+        assert!(glob_match("*-sys", "openssl_sys"));
+        assert!(glob_match("rand-*", "rand_core"));
+    }
+
+    #[test]
+    fn test_validate_crate_name() {
+        for name in ["serde", "serde_json", "proc-macro2", "Inflector", "a"] {
+            assert!(validate_crate_name(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "",
+            "serde json",
+            "2d",
+            "-serde",
+            "serde,log",
+            "\"serde\"",
+            &"a".repeat(65),
+        ] {
+            assert!(
+                matches!(validate_crate_name(name), Err(Error::InvalidCrateName(..))),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(
+            validate_crate_name("serde json").unwrap_err().to_string(),
+            "Invalid crate name \"serde json\", only letters, digits, - and _ are allowed"
+        );
+    }
+
+    #[test]
+    fn test_crate_name_spellings() {
+        assert!(same_crate_name("serde_json", "serde-json"));
+        assert!(same_crate_name("serde-json", "serde_json"));
+        assert!(!same_crate_name("serde_json", "serdejson"));
+
+        let lockfile: Lockfile = r#"
+version = 3
+
+[[package]]
+name = "serde_json"
+version = "1.0.64"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "proc-macro2"
+version = "1.0.24"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#
+        .parse()
+        .unwrap();
+        for name in ["serde_json", "serde-json"] {
+            assert_eq!(canonical_crate_name(&lockfile, name), "serde_json");
+        }
+        for name in ["proc-macro2", "proc_macro2"] {
+            assert_eq!(canonical_crate_name(&lockfile, name), "proc-macro2");
+        }
+        assert_eq!(canonical_crate_name(&lockfile, "rand_core"), "rand_core");
+    }
+
+    #[test]
+    fn test_version_req() {
+        let package = Package {
+            name: "serde".to_owned(),
+            version: "1.0.123".to_owned(),
+            locked: None,
+            checksum: None,
+        };
+        let req = package.version_req().unwrap();
+        assert_eq!(req.to_string(), "=1.0.123");
+        assert_eq!(package.to_string(), format!("serde = \"{}\"", req));
+        assert!(req.matches(&semver::Version::parse("1.0.123").unwrap()));
+        assert!(!req.matches(&semver::Version::parse("1.0.124").unwrap()));
+
+        let invalid = Package {
+            version: "latest".to_owned(),
+            ..package
+        };
+        assert!(invalid.version_req().is_err());
+    }
+
+    #[test]
+    fn test_to_patch_table() {
+        let packages = [
+            Package {
+                name: "serde".to_owned(),
+                version: "1.0.123".to_owned(),
+                locked: None,
+                checksum: None,
+            },
+            Package {
+                name: "log".to_owned(),
+                version: "0.4.14".to_owned(),
+                locked: None,
+                checksum: None,
+            },
+            Package {
+                name: "serde".to_owned(),
+                version: "1.0.123".to_owned(),
+                locked: None,
+                checksum: None,
+            },
+        ];
+        assert_eq!(
+            to_patch_table(&packages),
+            "[patch.crates-io]\nlog = { version = \"=0.4.14\" }\nserde = { version = \"=1.0.123\" }\n"
+        );
     }
 }
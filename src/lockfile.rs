@@ -0,0 +1,340 @@
+use std::path::Path;
+
+use cargo_lock::{Lockfile, MetadataKey};
+use log::info;
+use serde::Deserialize;
+
+use crate::{Dependency, DowngradeReport, Error, Package, Result, compatibility_key};
+
+/// Newest Cargo.lock format version that cargo_lock can read and write
+const NEWEST_VERSION: u32 = 4;
+
+/// Load the Cargo.lock at `path`, in any format version from 1 to 4.
+///
+/// Writing the lockfile again keeps its format version.
+pub fn load_lockfile(path: &Path) -> Result<Lockfile> {
+    parse_lockfile(&std::fs::read_to_string(path)?)
+}
+
+fn parse_lockfile(content: &str) -> Result<Lockfile> {
+    /// Only the explicit format version of V3 and newer lockfiles, V1 and V2 lockfiles have none
+    #[derive(Deserialize)]
+    struct FormatVersion {
+        version: Option<u32>,
+    }
+
+    // a lockfile written by a newer cargo would otherwise fail with a confusing parse error
+    if let Ok(FormatVersion {
+        version: Some(version),
+    }) = toml::from_str(content)
+        && version > NEWEST_VERSION
+    {
+        return Err(Error::UnsupportedLockfileVersion(version));
+    }
+    Ok(content.parse()?)
+}
+
+/// Set the versions of `packages` in `lockfile` directly, without asking cargo whether the requirements in
+/// Cargo.toml allow them.
+///
+/// The checksums of the changed packages are replaced by the ones of the new versions, or removed if the
+/// registry did not provide them, so that cargo does not reject the lockfile. Returns the packages that are
+/// not locked in `lockfile`.
+pub fn downgrade_lockfile<'p>(
+    lockfile: &mut Lockfile,
+    packages: &'p [Package],
+) -> Result<Vec<&'p Package>> {
+    let mut remaining = vec![];
+    for package in packages {
+        let Some(locked) = &package.locked else {
+            remaining.push(package);
+            continue;
+        };
+        let parse = |version: &str| {
+            semver::Version::parse(version)
+                .map_err(|err| Error::InvalidVersion(version.to_owned(), err))
+        };
+        let (old, new) = (parse(locked)?, parse(&package.version)?);
+
+        let Some(locked) = lockfile.packages.iter_mut().find(|locked| {
+            locked.name.as_str() == package.name && locked.version == old && locked.source.is_some()
+        }) else {
+            remaining.push(package);
+            continue;
+        };
+        // lockfiles in the V1 format keep the checksums in [metadata]
+        let key = MetadataKey::for_checksum(&cargo_lock::Dependency::from(&*locked));
+        lockfile.metadata.remove(&key);
+        locked.version = new.clone();
+        locked.checksum = match &package.checksum {
+            Some(checksum) => Some(
+                checksum
+                    .parse()
+                    .map_err(|err| Error::InvalidChecksum(checksum.clone(), err))?,
+            ),
+            None => None,
+        };
+        let source = locked.source.clone();
+
+        // dependencies on the package name its version if the crate is locked in several versions
+        let dependents = lockfile
+            .packages
+            .iter_mut()
+            .chain(lockfile.root.as_mut())
+            .flat_map(|dependent| {
+                dependent
+                    .dependencies
+                    .iter_mut()
+                    .chain(dependent.replace.as_mut())
+            });
+        for dependency in dependents {
+            if dependency.name.as_str() == package.name
+                && dependency.version == old
+                && (dependency.source.is_none() || dependency.source == source)
+            {
+                dependency.version = new.clone();
+            }
+        }
+    }
+    Ok(remaining)
+}
+
+/// Take the versions of `dependencies` from `snapshot`, an older Cargo.lock, instead of looking them up by
+/// date. Nothing is fetched.
+///
+/// If the snapshot locks a crate in several versions, the one compatible with the locked version is taken.
+/// Crates that are not in the snapshot are left out of the report, so they stay unchanged.
+pub fn versions_from_snapshot(dependencies: &[Dependency], snapshot: &Lockfile) -> DowngradeReport {
+    let mut report = DowngradeReport::default();
+    for dependency in dependencies {
+        let candidates: Vec<_> = snapshot
+            .packages
+            .iter()
+            .filter(|package| {
+                package.name.as_str() == dependency.name
+                    && package
+                        .source
+                        .as_ref()
+                        .is_some_and(|source| source.is_registry())
+            })
+            .collect();
+        let locked = dependency.locked.map(|package| &package.version);
+        let compatible = candidates.iter().find(|package| {
+            locked.is_some_and(|locked| {
+                compatibility_key(&package.version) == compatibility_key(locked)
+            })
+        });
+        let Some(package) = compatible.or(match &candidates[..] {
+            [only] => Some(only),
+            _ => None,
+        }) else {
+            info!(
+                "crate {} is not in the snapshot, left unchanged",
+                dependency.name
+            );
+            continue;
+        };
+        report.resolved.push(Package {
+            name: dependency.name.to_owned(),
+            version: package.version.to_string(),
+            locked: locked.map(ToString::to_string),
+            checksum: package.checksum.as_ref().map(ToString::to_string),
+        });
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use cargo_lock::ResolveVersion;
+
+    use super::*;
+
+    const LOCKFILE: &str = r#"# This file is automatically @generated by Cargo.
+# It is not intended for manual editing.
+version = 3
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = [
+ "rand 0.7.3",
+ "rand 0.8.3",
+]
+
+[[package]]
+name = "rand"
+version = "0.7.3"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "6a6b1679d49b24bbfe0c803429aa1874472f50d9b363131f0e89fc356b544d03"
+
+[[package]]
+name = "rand"
+version = "0.8.3"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "0ef9e7e66b4468674bfcb0c81af8b7fa0bb154fa9f28eb840da5c447baeb8d7e"
+"#;
+
+    #[test]
+    fn test_downgrade_lockfile() {
+        let mut lockfile: Lockfile = LOCKFILE.parse().unwrap();
+        let checksum = "a76330fb486679b4ace3670f117bbc9e16204005c4bde9c4bd372f45bed34f12";
+        let packages = [
+            Package {
+                name: "rand".to_owned(),
+                version: "0.7.0".to_owned(),
+                locked: Some("0.7.3".to_owned()),
+                checksum: None,
+            },
+            Package {
+                name: "rand".to_owned(),
+                version: "0.8.0".to_owned(),
+                locked: Some("0.8.3".to_owned()),
+                checksum: Some(checksum.to_owned()),
+            },
+            Package {
+                name: "serde".to_owned(),
+                version: "1.0.123".to_owned(),
+                locked: Some("1.0.124".to_owned()),
+                checksum: None,
+            },
+        ];
+
+        let remaining = downgrade_lockfile(&mut lockfile, &packages).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].name, "serde");
+
+        let edited = lockfile.to_string();
+        assert_eq!(
+            edited,
+            LOCKFILE
+                .replace("\"rand 0.7.3\"", "\"rand 0.7.0\"")
+                .replace("\"rand 0.8.3\"", "\"rand 0.8.0\"")
+                // without a checksum from the registry cargo adds it again on the next build
+                .replace(
+                    "version = \"0.7.3\"\nsource = \"registry+https://github.com/rust-lang/crates.io-index\"\nchecksum = \"6a6b1679d49b24bbfe0c803429aa1874472f50d9b363131f0e89fc356b544d03\"\n",
+                    "version = \"0.7.0\"\nsource = \"registry+https://github.com/rust-lang/crates.io-index\"\n"
+                )
+                .replace("version = \"0.8.3\"", "version = \"0.8.0\"")
+                .replace(
+                    "0ef9e7e66b4468674bfcb0c81af8b7fa0bb154fa9f28eb840da5c447baeb8d7e",
+                    checksum
+                )
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Cargo.lock");
+        std::fs::write(&path, &edited).unwrap();
+        assert_eq!(Lockfile::load(&path).unwrap(), lockfile);
+    }
+
+    const V1_LOCKFILE: &str = r#"[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = [
+ "rand 0.8.3 (registry+https://github.com/rust-lang/crates.io-index)",
+]
+
+[[package]]
+name = "rand"
+version = "0.8.3"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[metadata]
+"checksum rand 0.8.3 (registry+https://github.com/rust-lang/crates.io-index)" = "0ef9e7e66b4468674bfcb0c81af8b7fa0bb154fa9f28eb840da5c447baeb8d7e"
+"#;
+
+    const V4_LOCKFILE: &str = r#"version = 4
+
+[[package]]
+name = "app"
+version = "0.1.0"
+dependencies = [
+ "rand",
+]
+
+[[package]]
+name = "rand"
+version = "0.8.3"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "0ef9e7e66b4468674bfcb0c81af8b7fa0bb154fa9f28eb840da5c447baeb8d7e"
+"#;
+
+    #[test]
+    fn test_lockfile_format_versions() {
+        let checksum = "a76330fb486679b4ace3670f117bbc9e16204005c4bde9c4bd372f45bed34f12";
+        let packages = [Package {
+            name: "rand".to_owned(),
+            version: "0.8.0".to_owned(),
+            locked: Some("0.8.3".to_owned()),
+            checksum: Some(checksum.to_owned()),
+        }];
+
+        for (content, version) in [
+            (V1_LOCKFILE, ResolveVersion::V1),
+            (LOCKFILE, ResolveVersion::V3),
+            (V4_LOCKFILE, ResolveVersion::V4),
+        ] {
+            let mut lockfile = parse_lockfile(content).unwrap();
+            assert_eq!(lockfile.version, version);
+            assert!(
+                downgrade_lockfile(&mut lockfile, &packages)
+                    .unwrap()
+                    .is_empty()
+            );
+
+            let edited = lockfile.to_string();
+            let parsed = parse_lockfile(&edited).unwrap();
+            assert_eq!(parsed.version, version);
+            assert_eq!(parsed.packages, lockfile.packages);
+            assert!(!edited.contains("0.8.3"));
+            assert!(edited.contains(checksum));
+        }
+        // V1 keeps the checksums in [metadata]
+        let mut lockfile = parse_lockfile(V1_LOCKFILE).unwrap();
+        downgrade_lockfile(&mut lockfile, &packages).unwrap();
+        assert!(lockfile.to_string().contains(&format!(
+            "\"checksum rand 0.8.0 (registry+https://github.com/rust-lang/crates.io-index)\" = \"{}\"",
+            checksum
+        )));
+    }
+
+    #[test]
+    fn test_versions_from_snapshot() {
+        let current: Lockfile = LOCKFILE
+            .replace("0.7.3", "0.7.4")
+            .replace("0.8.3", "0.8.4")
+            .parse()
+            .unwrap();
+        let snapshot: Lockfile = LOCKFILE.parse().unwrap();
+        let dependencies: Vec<_> = ["rand", "serde"]
+            .iter()
+            .flat_map(|name| Dependency::all_from_lockfile(&current, name))
+            .collect();
+
+        let report = versions_from_snapshot(&dependencies, &snapshot);
+        let versions: Vec<_> = report
+            .resolved
+            .iter()
+            .map(|package| (package.locked.as_deref(), package.version.as_str()))
+            .collect();
+        // serde is not in the snapshot
+        assert_eq!(
+            versions,
+            [(Some("0.7.4"), "0.7.3"), (Some("0.8.4"), "0.8.3")]
+        );
+        assert_eq!(
+            report.resolved[1].checksum.as_deref(),
+            Some("0ef9e7e66b4468674bfcb0c81af8b7fa0bb154fa9f28eb840da5c447baeb8d7e")
+        );
+    }
+
+    #[test]
+    fn test_unsupported_lockfile_version() {
+        let content = V4_LOCKFILE.replace("version = 4", "version = 5");
+        assert!(matches!(
+            parse_lockfile(&content),
+            Err(Error::UnsupportedLockfileVersion(5))
+        ));
+    }
+}
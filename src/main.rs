@@ -1,31 +1,67 @@
-use chrono::DateTime;
-use clap::{Parser, Subcommand};
+use cargo_downgrade::{
+    Cache, Dependency, DependencyKind, FetchOptions, KindFilter, Package, SelectError,
+    SelectOptions, Strategy,
+};
+use chrono::{DateTime, SecondsFormat, TimeDelta};
+use clap::{CommandFactory, Parser, Subcommand};
 use error_reporter::Report;
+use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
     env::args_os,
-    io::{self, Write},
-    num::NonZeroU8,
-    path::PathBuf,
-    process::Command,
+    ffi::OsString,
+    fmt, fs,
+    io::{self, IsTerminal, Write},
+    num::{NonZeroU8, NonZeroUsize},
+    path::{Path, PathBuf},
+    process::{Command, ExitCode},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
+use thiserror::Error;
+use url::Url;
+
+mod json_log;
 
 #[derive(Debug, clap::Args)]
-#[group(required = true, multiple = false)]
+#[group(multiple = false)]
 pub struct Group {
-    /// Date to which the dependencies should be downgraded. In RFC 2822 format, e.g. "22 Feb 2021 23:16:09 GMT"
+    /// Date to which the dependencies should be downgraded. As YYYY-MM-DD, in RFC 3339 format, e.g. "2021-02-22T23:16:09Z", RFC 2822 format, e.g. "22 Feb 2021 23:16:09 GMT", or relative to now, e.g. "6 months ago"
     #[clap(long, short)]
     date: Option<String>,
 
-    /// Get the date from git
+    /// Get the date from the current git commit
     #[clap(long, action)]
     git: bool,
+
+    /// Get the date from a git commit, tag or branch, e.g. "v1.2.0"
+    #[clap(long, value_name = "REV")]
+    git_ref: Option<String>,
+
+    /// Get the date from the modification time of a file, e.g. a committed snapshot
+    #[clap(long, value_name = "PATH")]
+    date_from_file: Option<PathBuf>,
+
+    /// Instead of a date, pin the crates to their versions in this older Cargo.lock, without fetching
+    /// anything. Crates that are not in it are left unchanged.
+    #[clap(long, value_name = "PATH")]
+    to_lockfile: Option<PathBuf>,
 }
 
 #[derive(Parser, Debug)]
+#[clap(
+    bin_name = "cargo downgrade",
+    after_help = "Exit status:\n  0  all crates have been resolved (and downgraded with --run)\n  1  an error stopped the downgrade\n  2  no appropriate version was found for some crates, or they do not exist\n  3  cargo update failed for some crates"
+)]
 struct CliArguments {
     /// Path to the Cargo.lock file.
     cargo_lock: Option<PathBuf>,
 
+    /// Path to the Cargo.toml of the project, instead of giving the path to its Cargo.lock
+    #[clap(long, value_name = "PATH", conflicts_with = "cargo_lock")]
+    manifest_path: Option<PathBuf>,
+
     #[clap(flatten)]
     group: Group,
 
@@ -33,114 +69,2253 @@ struct CliArguments {
     #[clap(long, action)]
     run: bool,
 
+    /// Instead of running cargo update, pin the direct dependencies in the Cargo.toml given with
+    /// --manifest-path, or else the one next to Cargo.lock, to the downgraded versions and print a
+    /// [patch.crates-io] table for the transitive ones
+    #[clap(long, action, conflicts_with = "run")]
+    apply_toml: bool,
+
+    /// Print the cargo update commands that --run would execute instead of the downgrade plan,
+    /// one per line and quoted for the shell
+    #[clap(long, action, conflicts_with_all = ["run", "apply_toml"])]
+    print_commands: bool,
+
+    /// With --run, set the versions in Cargo.lock directly instead of running cargo update. The result may
+    /// not satisfy the requirements in Cargo.toml, which cargo would then fix on the next build.
+    #[clap(long, action, requires = "run")]
+    edit_lockfile: bool,
+
+    /// Also select a version for crates whose locked version has been published before the date, which may
+    /// upgrade them. By default they are left as they are. Implied by --force.
+    #[clap(long, action)]
+    no_skip_old: bool,
+
+    /// With --run, continue a run that has been interrupted or has died, skipping the crates it has already
+    /// downgraded according to <CARGO_LOCK>.downgrade-state.json and keeping its backup. Ctrl-C keeps the
+    /// progress instead of restoring Cargo.lock.
+    #[clap(long, action, requires = "run", conflicts_with = "edit_lockfile")]
+    resume: bool,
+
+    /// Comma-separated list of dependency kinds (normal, dev, build) of the workspace members to follow
+    /// when downgrading all dependencies [default: all kinds]
+    #[clap(long, value_enum, value_delimiter = ',')]
+    kinds: Vec<DependencyKind>,
+
+    /// Leave crates that are only reachable through dev-dependencies at their current version, same as
+    /// --kinds normal,build
+    #[clap(long, action, conflicts_with = "kinds")]
+    no_dev: bool,
+
+    /// Comma-separated list of crate names that should not be downgraded. `-` and `_` are interchangeable.
+    #[clap(long, value_delimiter = ',')]
+    exclude: Vec<String>,
+
+    /// Only downgrade crates locked from the registry with this index URL, e.g.
+    /// "https://github.com/rust-lang/crates.io-index" [default: all registries]
+    #[clap(long, value_name = "INDEX_URL")]
+    source: Option<Url>,
+
+    /// Only downgrade crates matching this glob pattern, e.g. "tokio*". Can be given multiple times.
+    #[clap(long, value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// Also consider yanked versions when selecting the version to downgrade to
+    #[clap(long, action)]
+    include_yanked: bool,
+
+    /// Also consider versions published exactly at the given date
+    #[clap(long, action)]
+    inclusive: bool,
+
+    /// Which of the versions published before the date to downgrade to
+    #[clap(long, value_enum, default_value_t = Strategy::Newest)]
+    strategy: Strategy,
+
+    /// Never downgrade a crate to a version that is semver incompatible with its locked version
+    #[clap(long, action)]
+    same_major: bool,
+
+    /// Also consider prerelease versions such as 1.0.0-beta.3, by default only stable versions are selected
+    #[clap(long, action)]
+    allow_prerelease: bool,
+
+    /// Minimum version of a crate, e.g. "serde@1.0.100". Can be given multiple times.
+    #[clap(long, value_name = "CRATE@VERSION", value_parser = parse_floor)]
+    floor: Vec<(String, semver::Version)>,
+
+    /// Only select versions that support this Rust version according to their rust-version, e.g. "1.56"
+    #[clap(long, value_name = "VERSION", value_parser = parse_msrv)]
+    msrv: Option<semver::Version>,
+
+    /// Downgrade a crate to a different date than the others, e.g. "tokio@2021-06-01". Can be given multiple times.
+    #[clap(long, value_name = "CRATE@DATE", value_parser = parse_crate_date)]
+    crate_date: Vec<(String, DateTime<chrono::Utc>)>,
+
+    /// Downgrade the crates that are only reachable through dev-dependencies to this date instead, e.g. to
+    /// keep test tooling newer than the runtime dependencies. Crates that are also reachable through normal
+    /// or build dependencies use the date of the others, and --crate-date takes precedence.
+    #[clap(long, value_name = "DATE", value_parser = cargo_downgrade::parse_date, conflicts_with = "to_lockfile")]
+    dev_date: Option<DateTime<chrono::Utc>>,
+
+    /// Ignore cached crates.io metadata and fetch everything again
+    #[clap(long, action)]
+    refresh_cache: bool,
+
+    /// Hours after which cached crates.io metadata is fetched again
+    #[clap(long, default_value_t = 24)]
+    cache_ttl_hours: u64,
+
+    /// Number of crates whose metadata is fetched concurrently
+    #[clap(long, short = 'j', default_value_t = NonZeroUsize::new(4).unwrap())]
+    jobs: NonZeroUsize,
+
+    /// Base URL of the web API of an alternative registry, e.g. "https://my-registry.example/api/v1/".
+    /// Crates that are locked from a registry other than crates.io are looked up there.
+    #[clap(long)]
+    registry: Option<Url>,
+
+    /// Look up crates.io crates in a local copy of the sparse index instead of the crates.io API
+    #[clap(long, value_name = "PATH")]
+    index: Option<PathBuf>,
+
+    /// Look up crates.io crates in the index cache cargo keeps in ~/.cargo/registry instead of the network.
+    /// Only crates that cargo has resolved before are found there.
+    #[clap(long, conflicts_with_all = ["index", "registry"])]
+    offline: bool,
+
+    /// Base URL of a crates.io API mirror to look up crates.io crates in, e.g. "https://mirror.example/api/v1/".
+    /// Without it, --index or --offline, a replacement of crates.io in .cargo/config.toml is used
+    #[clap(long, value_name = "URL", value_parser = parse_api_base, conflicts_with_all = ["index", "offline"])]
+    api_base: Option<Url>,
+
+    /// Proxy for the requests to registries, e.g. "http://proxy.example:3128".
+    /// Defaults to the proxy in the HTTPS_PROXY or ALL_PROXY environment variables.
+    #[clap(long, value_name = "URL")]
+    proxy: Option<Url>,
+
+    /// User-Agent sent to the registry, crates.io requires it to identify who is crawling
+    #[clap(long, default_value = cargo_downgrade::DEFAULT_USER_AGENT)]
+    user_agent: String,
+
+    /// Minimum interval in milliseconds between two requests to a registry.
+    /// Keep the default for crates.io, which asks crawlers to send at most one request per second.
+    #[clap(long, default_value_t = 1000)]
+    rate_limit_ms: u64,
+
+    /// Seconds after which a request to a registry is given up and retried
+    #[clap(long, default_value_t = 30)]
+    timeout_secs: u64,
+
+    /// Accept a date in the future, which selects the newest versions
+    #[clap(long, action)]
+    allow_future: bool,
+
+    /// Which date of the git commit to use with --git or --git-ref
+    #[clap(long, value_enum, default_value_t = GitDate::Committer)]
+    git_date: GitDate,
+
+    /// How often a request to a registry that timed out, lost its connection or failed with a server error is retried
+    #[clap(long, default_value_t = 2)]
+    retries: u32,
+
+    /// Where to back up Cargo.lock before running the downgrade [default: <CARGO_LOCK>.bak]
+    #[clap(long, value_name = "PATH")]
+    backup_path: Option<PathBuf>,
+
+    /// Do not ask for confirmation before running the downgrade
+    #[clap(long, short, action)]
+    yes: bool,
+
+    /// Overwrite an existing backup of Cargo.lock. Also run cargo update for crates that are already at their
+    /// target version and select a version for crates older than the date like --no-skip-old, e.g. to
+    /// repair corrupt checksums.
+    #[clap(long, action)]
+    force: bool,
+
+    /// Log more, -vv also logs trace messages. RUST_LOG takes precedence if set.
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Log less, -qq only logs errors. RUST_LOG takes precedence if set.
+    #[clap(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// Do not color the output, same as setting NO_COLOR
+    #[clap(long, action, global = true)]
+    no_color: bool,
+
+    /// Format of the log messages on stderr, e.g. json for one JSON object per message for log aggregation.
+    /// The downgrade plan on stdout keeps its --format.
+    #[clap(long, value_enum, default_value = "text", global = true)]
+    log_format: LogFormat,
+
+    /// Output format of the downgrade plan [default: github on GitHub Actions, text otherwise]
+    #[clap(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Read default options from this file instead of .downgrade.toml next to Cargo.lock. Flags given on the
+    /// command line take precedence over the file.
+    #[clap(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Only print the crates that would be downgraded and their dependency level as JSON, without fetching anything
+    #[clap(long, action, conflicts_with = "print_tree")]
+    list_deps: bool,
+
+    /// Print how many requests have been made, how many crates came from the cache and how long it took
+    /// to stderr after the run
+    #[clap(long, action)]
+    stats: bool,
+
+    /// Only print the dependency graph of the crates that would be downgraded, without fetching anything
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    print_tree: Option<TreeFormat>,
+
+    /// Write the downgrade plan to this file instead of stdout
+    #[clap(long, short, value_name = "PATH")]
+    output: Option<PathBuf>,
+
     #[clap(subcommand)]
     modes: DowngradeModes,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line with timestamp, level, target and message
+    Json,
+}
+
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    /// One `name = "=version"` line per crate
+    Text,
+    /// A JSON array of objects with `name` and `version`
+    Json,
+    /// A `[patch.crates-io]` table to paste into Cargo.toml
+    Patch,
+    /// Like text, with GitHub Actions annotations for skipped and failed crates.
+    /// The default when GITHUB_ACTIONS=true.
+    Github,
+}
+
+impl OutputFormat {
+    /// The format to use when none has been given on the command line or in the config file
+    fn from_env() -> Self {
+        match std::env::var("GITHUB_ACTIONS") {
+            Ok(value) if value == "true" => OutputFormat::Github,
+            _ => OutputFormat::Text,
+        }
+    }
+
+    /// Whether the output is meant to be read by humans instead of programs
+    fn is_text(self) -> bool {
+        matches!(self, OutputFormat::Text | OutputFormat::Github)
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TreeFormat {
+    /// A Graphviz graph, e.g. for `dot -Tsvg`
+    Dot,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum GitDate {
+    /// When the commit was originally written
+    Author,
+    /// When the commit was last applied, e.g. by a rebase
+    Committer,
+}
+
 #[derive(Subcommand, Debug)]
 enum DowngradeModes {
-    /// Downgrade all crate names of transitive dependencies in Cargo.lock file up to `dependency_level`
+    /// Downgrade the transitive dependencies in Cargo.lock, on all levels or only on `dependency_level`
     All {
-        /// Dependency level to which transitive dependencies of the crate should be downgraded.
+        /// Only downgrade the crates on exactly this level below the root crates, e.g. 2 for the dependencies
+        /// of the direct dependencies. Add --cumulative to downgrade the crates on levels 1 to N instead.
         #[clap(long, short = 'l')]
         dependency_level: Option<NonZeroU8>,
+
+        /// With --dependency-level N, downgrade the crates on all levels from 1 to N instead of only those
+        /// on level N
+        #[clap(long, action, requires = "dependency_level")]
+        cumulative: bool,
+
+        /// Also downgrade the root crates that nothing else depends on. Local roots such as workspace members
+        /// are skipped, since they are not in a registry.
+        #[clap(long, action, conflicts_with = "direct_only")]
+        include_roots: bool,
+
+        /// Only downgrade the crates that a workspace member depends on directly. Unlike --dependency-level 1,
+        /// which only looks at the crates on level 1 below the root crates, this includes the direct
+        /// dependencies of workspace members that other members depend on.
+        #[clap(long, action, conflicts_with = "dependency_level")]
+        direct_only: bool,
     },
 
     /// Downgrade a list of specific crates
     This {
-        /// Comma-separated list of crate names to downgrade
-        #[clap(value_delimiter = ',', required = true)]
+        /// Comma-separated list of crate names to downgrade, or - to read them from stdin like --crates-file
+        #[clap(value_delimiter = ',', required_unless_present = "crates_file")]
         crates: Vec<String>,
+
+        /// Read more crate names from this file, separated by whitespace or commas. Blank lines and
+        /// everything after a # are ignored.
+        #[clap(long, value_name = "PATH")]
+        crates_file: Option<PathBuf>,
+    },
+
+    /// Restore Cargo.lock from the backup made by --run
+    Restore,
+
+    /// Print the date that a downgrade would use, in RFC 3339
+    Date,
+
+    /// Print the version of a single crate that would be selected for the date, without a lockfile
+    Query {
+        /// Name of the crate to look up
+        #[clap(value_name = "CRATE")]
+        crate_name: String,
+    },
+
+    /// Find the date at which a test command starts to fail by downgrading all crates to dates between a
+    /// good and a bad date, like git bisect. Cargo.lock is restored afterwards. The crates are downgraded with
+    /// cargo update, or directly in Cargo.lock with --run --edit-lockfile.
+    Bisect {
+        /// A date at which the test command passes
+        #[clap(long, value_parser = cargo_downgrade::parse_date)]
+        good: DateTime<chrono::Utc>,
+
+        /// A date at which the test command fails
+        #[clap(long, value_parser = cargo_downgrade::parse_date)]
+        bad: DateTime<chrono::Utc>,
+
+        /// The test command, e.g. "cargo build", which passes if it exits successfully
+        #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
     },
+
+    /// Print the completion script for a shell, e.g. to source in ~/.bashrc with
+    /// `source <(cargo-downgrade completions bash)`
+    #[clap(hide = true)]
+    Completions {
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+
+    /// Print the man page in roff, or write it to a file, e.g. for packaging
+    #[clap(hide = true)]
+    Man {
+        /// Write the man page to this file instead of stdout
+        #[clap(value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+}
+
+fn parse_floor(floor: &str) -> Result<(String, semver::Version), String> {
+    let (name, version) = floor
+        .split_once('@')
+        .ok_or_else(|| format!("expected CRATE@VERSION, got {:?}", floor))?;
+    let version = semver::Version::parse(version).map_err(|err| err.to_string())?;
+    Ok((name.to_owned(), version))
 }
 
-fn get_timestamp_from_git() -> Option<DateTime<chrono::Utc>> {
+/// The crate names in a crate list, separated by whitespace or commas, with `#` starting a comment
+fn parse_crate_list(content: &str) -> impl Iterator<Item = String> + '_ {
+    content
+        .lines()
+        .map(|line| line.split_once('#').map_or(line, |(names, _comment)| names))
+        .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+}
+
+fn parse_msrv(msrv: &str) -> Result<semver::Version, String> {
+    cargo_downgrade::parse_rust_version(msrv)
+        .ok_or_else(|| format!("expected a Rust version like 1.56, got {:?}", msrv))
+}
+
+fn parse_api_base(api_base: &str) -> Result<Url, String> {
+    let url = Url::parse(api_base).map_err(|err| format!("invalid URL {:?}: {}", api_base, err))?;
+    if !matches!(url.scheme(), "http" | "https") || url.cannot_be_a_base() {
+        return Err(format!("expected an http(s) URL, got {:?}", api_base));
+    }
+    Ok(url)
+}
+
+fn parse_crate_date(crate_date: &str) -> Result<(String, DateTime<chrono::Utc>), String> {
+    let (name, date) = crate_date
+        .split_once('@')
+        .ok_or_else(|| format!("expected CRATE@DATE, got {:?}", crate_date))?;
+    let date = cargo_downgrade::parse_date(date).map_err(|err| err.to_string())?;
+    Ok((name.to_owned(), date))
+}
+
+fn get_timestamp_from_git(rev: &str, git_date: GitDate) -> Result<DateTime<chrono::Utc>, CliError> {
     let mut input = Command::new("git");
 
-    input.arg("show").arg("-s").arg("--format=%ct");
-    let output = input.output().ok()?;
-    let stdout = String::from_utf8(output.stdout).ok()?;
-    let secs = stdout.trim().parse().ok()?;
+    // `^{commit}` peels annotated tags, whose details `git show` would print as well
+    input
+        .arg("show")
+        .arg("-s")
+        .arg(match git_date {
+            GitDate::Author => "--format=%at",
+            GitDate::Committer => "--format=%ct",
+        })
+        .arg(format!("{}^{{commit}}", rev))
+        .arg("--");
+    let output = input
+        .output()
+        .map_err(|err| missing_program(err, "git", "install git or pass --date", CliError::Git))?;
+    if !output.status.success() {
+        return Err(CliError::GitRev(
+            rev.to_owned(),
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let datetime = stdout
+        .trim()
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .ok_or_else(|| CliError::GitTimestamp(stdout.trim().to_owned()))?;
 
-    let datetime: DateTime<chrono::Utc> =
-        DateTime::from_timestamp(secs, 0)?.with_timezone(&chrono::Utc);
+    Ok(datetime)
+}
 
-    Some(datetime)
+/// The date of reproducible builds, see <https://reproducible-builds.org/specs/source-date-epoch/>
+fn source_date_epoch() -> Result<Option<DateTime<chrono::Utc>>, CliError> {
+    let Some(epoch) = std::env::var_os("SOURCE_DATE_EPOCH") else {
+        return Ok(None);
+    };
+    let epoch = epoch.to_string_lossy();
+    epoch
+        .trim()
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map(Some)
+        .ok_or_else(|| CliError::SourceDateEpoch(epoch.into_owned()))
 }
 
-#[tokio::main]
-async fn main() {
-    simple_logger::init_with_level(log::Level::Info).unwrap();
-    let args = CliArguments::parse_from(
-        args_os()
-            .into_iter()
-            .enumerate()
-            .filter(|(i, _str)| {
-                *i != 0 // || str.to_str().unwrap() == "downgrade"
-            })
-            .map(|(_, str)| str),
-    );
+fn get_timestamp_from_file(path: &Path) -> Result<DateTime<chrono::Utc>, CliError> {
+    let modified = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|err| CliError::DateFile(path.to_owned(), err))?;
+    Ok(modified.into())
+}
+
+#[derive(Error, Debug)]
+enum CliError {
+    #[error("could not determine the current directory")]
+    CurrentDir(#[source] io::Error),
+    /// The Cargo.lock is missing, with whether --manifest-path was passed, which is suggested otherwise
+    #[error(
+        "no Cargo.lock found for {}, run `cargo generate-lockfile` to create one{}",
+        .0.display(),
+        if *.1 { "" } else { " or pass --manifest-path" }
+    )]
+    NoLockfile(PathBuf, bool),
+    #[error("could not read the crate list {}", .0.display())]
+    ReadCratesFile(PathBuf, #[source] io::Error),
+    #[error("no appropriate version of {0}: {1}")]
+    Query(String, SelectError),
+    #[error("could not read the crate list from stdin")]
+    ReadStdin(#[source] io::Error),
+    #[error("could not read the config file {}", .0.display())]
+    ReadConfig(PathBuf, #[source] io::Error),
+    #[error("invalid config file {}", .0.display())]
+    ParseConfig(PathBuf, #[source] toml::de::Error),
+    #[error("could not load Cargo.lock at {}", .0.display())]
+    LoadLockfile(PathBuf, #[source] cargo_downgrade::Error),
+    #[error("could not build the dependency tree of Cargo.lock")]
+    DependencyTree(#[source] cargo_lock::Error),
+    #[error("{0} not found on PATH, {1}")]
+    MissingProgram(&'static str, &'static str),
+    #[error("failed to run git")]
+    Git(#[source] io::Error),
+    #[error("git could not resolve {0:?}: {1}")]
+    GitRev(String, String),
+    #[error("unexpected commit timestamp {0:?} from git")]
+    GitTimestamp(String),
+    #[error(
+        "the date {0} is in the future, did you mean a date in the past? Pass --allow-future to use it anyway"
+    )]
+    FutureDate(DateTime<chrono::Utc>),
+    #[error(
+        "no date given, pass --date, --git, --git-ref or --date-from-file, or set SOURCE_DATE_EPOCH"
+    )]
+    NoDate,
+    #[error("SOURCE_DATE_EPOCH must be a Unix timestamp, got {0:?}")]
+    SourceDateEpoch(String),
+    #[error("could not read the modification time of {}", .0.display())]
+    DateFile(PathBuf, #[source] io::Error),
+    #[error("a backup already exists at {}, restore it or pass --force to overwrite it", .0.display())]
+    BackupExists(PathBuf),
+    #[error("could not back up Cargo.lock to {}", .0.display())]
+    Backup(PathBuf, #[source] io::Error),
+    #[error("could not restore Cargo.lock from {}", .0.display())]
+    Restore(PathBuf, #[source] io::Error),
+    #[error("interrupted, {} restored", .0.display())]
+    Interrupted(PathBuf),
+    #[error("interrupted, run again with --resume to continue downgrading {}", .0.display())]
+    Paused(PathBuf),
+    #[error("could not read the state file {}", .0.display())]
+    ReadState(PathBuf, #[source] io::Error),
+    #[error("could not parse the state file {}", .0.display())]
+    ParseState(PathBuf, #[source] serde_json::Error),
+    #[error("could not write the state file {}", .0.display())]
+    WriteState(PathBuf, #[source] io::Error),
+    #[error("failed to run the test command {0:?}")]
+    TestCommand(String, #[source] io::Error),
+    #[error("the test command {0} at the {1} date {2}, the dates cannot be bisected")]
+    BisectEndpoint(&'static str, &'static str, DateTime<chrono::Utc>),
+    #[error(
+        "the test command passes at the good date and fails at the bad date, but the versions are the same, is it flaky?"
+    )]
+    BisectSameVersions,
+    #[error("could not read {}", .0.display())]
+    ReadManifest(PathBuf, #[source] io::Error),
+    #[error("could not write {}", .0.display())]
+    WriteManifest(PathBuf, #[source] io::Error),
+    #[error("could not write {}", .0.display())]
+    WriteLockfile(PathBuf, #[source] io::Error),
+    #[error("failed to run cargo metadata")]
+    CargoMetadata(#[source] io::Error),
+    #[error("cargo metadata failed: {0}")]
+    CargoMetadataFailed(String),
+    #[error("unexpected output from cargo metadata")]
+    DecodeMetadata(#[source] serde_json::Error),
+    #[error("could not read the answer to the confirmation prompt")]
+    Prompt(#[source] io::Error),
+    #[error("failed to run cargo update")]
+    CargoUpdate(#[source] io::Error),
+    #[error("cargo update failed for {0} crates")]
+    UpdateFailed(usize),
+    #[error("could not quote the cargo update command for the shell")]
+    Quote(#[source] shlex::QuoteError),
+    #[error("failed to write output")]
+    Output(#[source] io::Error),
+    #[error("could not write the downgrade plan to {}", .0.display())]
+    WriteOutput(PathBuf, #[source] io::Error),
+    #[error("failed to serialize the downgrade plan")]
+    Serialize(#[source] serde_json::Error),
+    #[error(transparent)]
+    Downgrade(#[from] cargo_downgrade::Error),
+}
+
+impl CliError {
+    /// Exit code that tells CI whether the run failed completely or only for some crates
+    fn exit_code(&self) -> ExitCode {
+        match self {
+            CliError::Downgrade(
+                cargo_downgrade::Error::NoAppropriateVersion
+                | cargo_downgrade::Error::CrateNotFound(_),
+            ) => ExitCode::from(2),
+            CliError::UpdateFailed(_) => ExitCode::from(3),
+            CliError::Query(..) => ExitCode::from(2),
+            // like a shell reports a process killed by SIGINT
+            CliError::Interrupted(_) | CliError::Paused(_) => ExitCode::from(130),
+            _ => ExitCode::FAILURE,
+        }
+    }
+}
+
+/// Turn the error of starting `program` into a hint how to get along without it if it is not installed
+fn missing_program(
+    err: io::Error,
+    program: &'static str,
+    hint: &'static str,
+    other: fn(io::Error) -> CliError,
+) -> CliError {
+    match err.kind() {
+        io::ErrorKind::NotFound => CliError::MissingProgram(program, hint),
+        _ => other(err),
+    }
+}
+
+/// Default options from `.downgrade.toml`, which are used for the flags that are not given on the command line
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Config {
+    date: Option<String>,
+    git_ref: Option<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// Minimum versions by crate name, overridden per crate by --floor
+    #[serde(default, deserialize_with = "deserialize_floors")]
+    floor: Vec<(String, semver::Version)>,
+    format: Option<OutputFormat>,
+}
+
+fn deserialize_floors<'de, D>(deserializer: D) -> Result<Vec<(String, semver::Version)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let floors: HashMap<String, String> = HashMap::deserialize(deserializer)?;
+    floors
+        .into_iter()
+        .map(|(name, version)| {
+            semver::Version::parse(&version)
+                .map(|version| (name, version))
+                .map_err(serde::de::Error::custom)
+        })
+        .collect()
+}
 
-    let lock_path = match args.cargo_lock {
-        Some(path) => path,
+/// Load the config file at `path`, or `.downgrade.toml` next to the lockfile if it exists
+fn load_config(path: Option<&Path>, lock_path: &Path) -> Result<Config, CliError> {
+    let path = match path {
+        Some(path) => path.to_owned(),
         None => {
-            let mut path = std::env::current_dir().unwrap();
+            let path = lock_path.with_file_name(".downgrade.toml");
+            if !path.is_file() {
+                return Ok(Config::default());
+            }
+            path
+        }
+    };
+    let content =
+        fs::read_to_string(&path).map_err(|err| CliError::ReadConfig(path.clone(), err))?;
+    let config =
+        toml::from_str(&content).map_err(|err| CliError::ParseConfig(path.clone(), err))?;
+    log::debug!("loaded options from {}", path.display());
+    Ok(config)
+}
+
+/// Fill in the options that are not given on the command line from `config` and return the output format.
+/// Flags given on the command line take precedence over SOURCE_DATE_EPOCH, which takes precedence over the
+/// config file. The floors are merged per crate.
+fn apply_config(
+    args: &mut CliArguments,
+    config: Config,
+    source_date_epoch: &Result<Option<DateTime<chrono::Utc>>, CliError>,
+) -> OutputFormat {
+    if args.group.date.is_none()
+        && !args.group.git
+        && args.group.git_ref.is_none()
+        && args.group.date_from_file.is_none()
+        && matches!(source_date_epoch, Ok(None))
+    {
+        args.group.date = config.date;
+        args.group.git_ref = config.git_ref;
+    }
+    if args.exclude.is_empty() {
+        args.exclude = config.exclude;
+    }
+    let cli_floors = std::mem::take(&mut args.floor);
+    args.floor = config.floor.into_iter().chain(cli_floors).collect();
+    args.format
+        .or(config.format)
+        .unwrap_or_else(OutputFormat::from_env)
+}
+
+/// The date to downgrade to, from the date flags, SOURCE_DATE_EPOCH or the config file in this order
+fn resolve_date(
+    args: &CliArguments,
+    source_date_epoch: Result<Option<DateTime<chrono::Utc>>, CliError>,
+) -> Result<DateTime<chrono::Utc>, CliError> {
+    let group = &args.group;
+    let datetime = match (
+        &group.date,
+        group.git,
+        &group.git_ref,
+        &group.date_from_file,
+    ) {
+        (Some(date), _, _, _) => cargo_downgrade::parse_date(date)?,
+        (None, true, _, _) => get_timestamp_from_git("HEAD", args.git_date)?,
+        (None, false, Some(rev), _) => get_timestamp_from_git(rev, args.git_date)?,
+        (None, false, None, Some(path)) => get_timestamp_from_file(path)?,
+        (None, false, None, None) => source_date_epoch?.ok_or(CliError::NoDate)?,
+    };
+    // a date in the future selects the newest versions, which is most likely a typo
+    if datetime > chrono::Utc::now() && !args.allow_future {
+        return Err(CliError::FutureDate(datetime));
+    }
+    Ok(datetime)
+}
+
+fn fetch_options(args: &CliArguments, format: OutputFormat, lock_path: &Path) -> FetchOptions {
+    let cache = Cache::default_dir().map(|dir| {
+        Cache::new(dir, Duration::from_secs(args.cache_ttl_hours * 60 * 60))
+            .refresh(args.refresh_cache)
+    });
+    if cache.is_none() {
+        log::warn!("no cache directory found, crates.io metadata will not be cached");
+    }
+    FetchOptions {
+        cache,
+        jobs: args.jobs,
+        registry: args.registry.clone(),
+        index: args.index.clone(),
+        offline: args.offline,
+        proxy: args.proxy.clone(),
+        api_base: args.api_base.clone(),
+        // the source replacement in .cargo/config.toml of the project applies, like for cargo itself
+        cargo_config_dir: lock_path.parent().map(Path::to_owned),
+        user_agent: args.user_agent.clone(),
+        rate_limit: Duration::from_millis(args.rate_limit_ms),
+        timeout: Duration::from_secs(args.timeout_secs),
+        retries: args.retries,
+        // only draw the bar for someone watching, not for scripts reading the output
+        progress: io::stdout().is_terminal()
+            && args.quiet == 0
+            && format != OutputFormat::Json
+            && args.log_format == LogFormat::Text,
+    }
+}
+
+fn select_options(args: &CliArguments) -> SelectOptions {
+    SelectOptions {
+        strategy: args.strategy,
+        include_yanked: args.include_yanked,
+        inclusive: args.inclusive,
+        same_major: args.same_major,
+        // with - and _ unified, a --floor overrides the floor of the config file however either is spelled
+        floors: args
+            .floor
+            .iter()
+            .map(|(name, version)| (name.replace('-', "_"), version.clone()))
+            .collect(),
+        crate_dates: args.crate_date.iter().cloned().collect(),
+        msrv: args.msrv.clone(),
+        allow_prerelease: args.allow_prerelease,
+        skip_old: !args.no_skip_old && !args.force,
+    }
+}
+
+/// Print the version of `crate_name` that would be selected for `date`
+async fn query(
+    crate_name: &str,
+    date: DateTime<chrono::Utc>,
+    args: &CliArguments,
+    format: OutputFormat,
+    lock_path: &Path,
+) -> Result<(), CliError> {
+    // the name ends up in the request URL and the path of the cache file
+    cargo_downgrade::validate_crate_name(crate_name)?;
+    let dependencies = [Dependency {
+        name: crate_name,
+        locked: None,
+    }];
+    let report = cargo_downgrade::get_downgraded_dependencies(
+        &dependencies,
+        date,
+        &select_options(args),
+        &fetch_options(args, format, lock_path),
+    )
+    .await?;
+    if let Some(name) = report.not_found.into_iter().next() {
+        return Err(cargo_downgrade::Error::CrateNotFound(name).into());
+    }
+    if let Some((_, err)) = report.failed.into_iter().next() {
+        return Err(CliError::Query(crate_name.to_owned(), err));
+    }
+    // a crate without a lockfile entry is always looked up on crates.io, so it is never skipped
+    let package = report
+        .resolved
+        .into_iter()
+        .next()
+        .expect("the crate is either resolved or failed");
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&package).map_err(CliError::Serialize)?
+        ),
+        _ => println!("{}", package),
+    }
+    Ok(())
+}
+
+/// Everything the bisect subcommand needs to downgrade to a date and run the test command
+struct Bisection<'a> {
+    args: &'a CliArguments,
+    format: OutputFormat,
+    dependencies: &'a [Dependency<'a>],
+    lock_path: &'a Path,
+    command: &'a [String],
+}
+
+impl Bisection<'_> {
+    /// Narrow the window between `good` and `bad` down to a second and print the crates that changed in it.
+    /// Cargo.lock is restored whatever the outcome.
+    async fn run(
+        &self,
+        good: DateTime<chrono::Utc>,
+        bad: DateTime<chrono::Utc>,
+    ) -> Result<(), CliError> {
+        let original = fs::read_to_string(self.lock_path)
+            .map_err(|err| CliError::LoadLockfile(self.lock_path.to_owned(), err.into()))?;
+        catch_interrupts().await;
+        let result = self.bisect(&original, good, bad).await;
+        write_atomically(self.lock_path, &original, CliError::WriteLockfile)?;
+        log::info!("restored {}", self.lock_path.display());
+        result
+    }
+
+    async fn bisect(
+        &self,
+        original: &str,
+        good: DateTime<chrono::Utc>,
+        bad: DateTime<chrono::Utc>,
+    ) -> Result<(), CliError> {
+        let BisectWindow {
+            good,
+            good_plan,
+            bad,
+            bad_plan,
+        } = bisect_dates(
+            good,
+            bad,
+            async |date| self.plan(date).await,
+            |plan| self.test(original, plan),
+        )
+        .await?;
+
+        println!(
+            "the test command starts to {} between {} and {}",
+            if good < bad { "fail" } else { "pass" },
+            good.min(bad).to_rfc3339_opts(SecondsFormat::Secs, true),
+            good.max(bad).to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        for bad_package in &bad_plan {
+            let good_version = good_plan
+                .iter()
+                .find(|package| {
+                    package.name == bad_package.name && package.locked == bad_package.locked
+                })
+                .map(|package| package.version.as_str());
+            if good_version != Some(bad_package.version.as_str()) {
+                println!(
+                    "{}  {} (good) -> {} (bad)",
+                    bad_package.name,
+                    good_version.unwrap_or("locked"),
+                    bad_package.version
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The versions to downgrade to at `date`
+    async fn plan(&self, date: DateTime<chrono::Utc>) -> Result<Vec<Package>, CliError> {
+        let report = cargo_downgrade::get_downgraded_dependencies(
+            self.dependencies,
+            date,
+            &select_options(self.args),
+            &fetch_options(self.args, self.format, self.lock_path),
+        )
+        .await?;
+        Ok(report.resolved)
+    }
+
+    /// Downgrade the `original` Cargo.lock to `plan` and return whether the test command passes
+    fn test(&self, original: &str, plan: &[Package]) -> Result<bool, CliError> {
+        // the locked versions in `plan` refer to the original Cargo.lock
+        write_atomically(self.lock_path, original, CliError::WriteLockfile)?;
+        let changed: Vec<Package> = plan
+            .iter()
+            .filter(|package| package.locked.as_ref() != Some(&package.version))
+            .cloned()
+            .collect();
+        let manifest_dir = self.lock_path.parent().unwrap_or(Path::new(""));
+        if self.args.edit_lockfile {
+            let mut lockfile = cargo_downgrade::load_lockfile(self.lock_path)
+                .map_err(|err| CliError::LoadLockfile(self.lock_path.to_owned(), err))?;
+            cargo_downgrade::downgrade_lockfile(&mut lockfile, &changed)?;
+            write_atomically(
+                self.lock_path,
+                &lockfile.to_string(),
+                CliError::WriteLockfile,
+            )?;
+        } else {
+            let report = cargo_downgrade::apply_downgrades(&changed, manifest_dir, &INTERRUPTED)
+                .map_err(|err| match err {
+                    cargo_downgrade::Error::Interrupted => {
+                        CliError::Interrupted(self.lock_path.to_owned())
+                    }
+                    err => err.into(),
+                })?;
+            for (package, reason) in &report.failed {
+                log::warn!("{} stays at its locked version: {}", package.name, reason);
+            }
+        }
+
+        let (program, arguments) = self.command.split_first().expect("the command is required");
+        let status = Command::new(program)
+            .args(arguments)
+            .current_dir(manifest_dir)
+            .status()
+            .map_err(|err| CliError::TestCommand(self.command.join(" "), err))?;
+        // the test command has been interrupted as well, so its result means nothing
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            return Err(CliError::Interrupted(self.lock_path.to_owned()));
+        }
+        log::info!(
+            "the test command {}",
+            if status.success() { "passed" } else { "failed" }
+        );
+        Ok(status.success())
+    }
+}
+
+/// The dates around the change of the result of the test command that [`bisect_dates`] has found, with the
+/// versions at them
+struct BisectWindow {
+    good: DateTime<chrono::Utc>,
+    good_plan: Vec<Package>,
+    bad: DateTime<chrono::Utc>,
+    bad_plan: Vec<Package>,
+}
+
+/// Narrow the window between `good` and `bad` down to a second by binary search. `plan` gives the versions
+/// at a date and `test` whether the test command passes with them. `bad` may be before `good` to find when
+/// the test command starts to pass.
+async fn bisect_dates(
+    mut good: DateTime<chrono::Utc>,
+    mut bad: DateTime<chrono::Utc>,
+    mut plan: impl AsyncFnMut(DateTime<chrono::Utc>) -> Result<Vec<Package>, CliError>,
+    mut test: impl FnMut(&[Package]) -> Result<bool, CliError>,
+) -> Result<BisectWindow, CliError> {
+    let mut good_plan = plan(good).await?;
+    if !test(&good_plan)? {
+        return Err(CliError::BisectEndpoint("fails", "good", good));
+    }
+    let mut bad_plan = plan(bad).await?;
+    if test(&bad_plan)? {
+        return Err(CliError::BisectEndpoint("passes", "bad", bad));
+    }
+    if same_versions(&good_plan, &bad_plan) {
+        return Err(CliError::BisectSameVersions);
+    }
+
+    // the versions only change when a crate is published, so most steps need no test run
+    while (bad - good).abs() > TimeDelta::seconds(1) {
+        let middle = good + (bad - good) / 2;
+        let plan = plan(middle).await?;
+        let passed = if same_versions(&plan, &good_plan) {
+            true
+        } else if same_versions(&plan, &bad_plan) {
+            false
+        } else {
+            log::info!("testing {}", middle);
+            test(&plan)?
+        };
+        if passed {
+            (good, good_plan) = (middle, plan);
+        } else {
+            (bad, bad_plan) = (middle, plan);
+        }
+    }
+    Ok(BisectWindow {
+        good,
+        good_plan,
+        bad,
+        bad_plan,
+    })
+}
+
+/// Set when Ctrl-C has been pressed after [`catch_interrupts`]
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Set [`INTERRUPTED`] on Ctrl-C instead of killing the process, so that Cargo.lock can be restored. Child
+/// processes such as cargo update still receive the interrupt.
+async fn catch_interrupts() {
+    let mut ctrl_c = Box::pin(tokio::signal::ctrl_c());
+    // the handler is installed when the future is polled for the first time
+    if futures::poll!(&mut ctrl_c).is_pending() {
+        tokio::spawn(async move {
+            if ctrl_c.await.is_ok() {
+                log::warn!("interrupted, stopping");
+                INTERRUPTED.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+/// Whether two downgrade plans select the same versions
+fn same_versions(a: &[Package], b: &[Package]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.name == b.name && a.locked == b.locked && a.version == b.version)
+}
+
+/// Progress of a --run, to continue it with --resume if it is interrupted
+#[derive(Serialize, Deserialize)]
+struct ResumeState {
+    /// The date the run downgrades to, `None` for --to-lockfile
+    date: Option<DateTime<chrono::Utc>>,
+    /// The crates that have been downgraded, as name@version
+    applied: Vec<String>,
+}
+
+/// The state file of a --run on `lock_path`
+fn resume_state_path(lock_path: &Path) -> PathBuf {
+    let mut path = lock_path.to_owned().into_os_string();
+    path.push(".downgrade-state.json");
+    PathBuf::from(path)
+}
+
+impl ResumeState {
+    fn key(package: &Package) -> String {
+        format!("{}@{}", package.name, package.version)
+    }
+
+    /// The state left behind by an interrupted run, if there is one
+    fn load(path: &Path) -> Result<Option<Self>, CliError> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(CliError::ReadState(path.to_owned(), err)),
+        };
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|err| CliError::ParseState(path.to_owned(), err))
+    }
+
+    fn store(&self, path: &Path) -> Result<(), CliError> {
+        let content = serde_json::to_string_pretty(self).map_err(CliError::Serialize)?;
+        write_atomically(path, &content, CliError::WriteState)
+    }
+
+    /// Leave out the packages that the interrupted run has already downgraded
+    fn skip_applied(&self, packages: &mut Vec<&Package>) {
+        packages.retain(|package| !self.applied.contains(&Self::key(package)));
+    }
+
+    /// Remove the state file once the run is complete
+    fn remove(path: &Path) {
+        if let Err(err) = fs::remove_file(path)
+            && err.kind() != io::ErrorKind::NotFound
+        {
+            log::warn!(
+                "could not remove the state file {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// A crate selected for downgrading as printed by --list-deps
+#[derive(Serialize)]
+struct ListedDependency<'a> {
+    name: &'a str,
+    /// Distance from the root crates, `None` if the crate is not in the dependency tree
+    level: Option<u8>,
+}
+
+/// The part of the output of `cargo metadata` that we are interested in
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<MetadataPackage>,
+}
+
+#[derive(Deserialize)]
+struct MetadataPackage {
+    name: String,
+    dependencies: Vec<MetadataDependency>,
+}
+
+#[derive(Deserialize)]
+struct MetadataDependency {
+    name: String,
+    kind: Option<String>,
+}
+
+/// Ask cargo for the kinds of the dependencies of the workspace members of the manifest next to `lock_path`
+fn get_workspace_kinds(
+    lock_path: &Path,
+) -> Result<HashMap<(String, String), HashSet<DependencyKind>>, CliError> {
+    let output = Command::new("cargo")
+        .arg("metadata")
+        .arg("--no-deps")
+        .arg("--format-version=1")
+        .arg("--manifest-path")
+        .arg(lock_path.with_file_name("Cargo.toml"))
+        .output()
+        .map_err(|err| {
+            missing_program(
+                err,
+                "cargo",
+                "install Rust or leave out --kinds, --no-dev and --dev-date",
+                CliError::CargoMetadata,
+            )
+        })?;
+    if !output.status.success() {
+        return Err(CliError::CargoMetadataFailed(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    let metadata: Metadata =
+        serde_json::from_slice(&output.stdout).map_err(CliError::DecodeMetadata)?;
+
+    let mut workspace: HashMap<_, HashSet<_>> = HashMap::new();
+    for package in metadata.packages {
+        for dependency in package.dependencies {
+            let kind = match dependency.kind.as_deref() {
+                Some("dev") => DependencyKind::Dev,
+                Some("build") => DependencyKind::Build,
+                _ => DependencyKind::Normal,
+            };
+            workspace
+                .entry((package.name.clone(), dependency.name))
+                .or_default()
+                .insert(kind);
+        }
+    }
+    Ok(workspace)
+}
+
+/// The Cargo.lock of the manifest at `manifest_path`, which is next to it or, for workspace members, in
+/// one of its parent directories
+fn find_lockfile(manifest_path: &Path) -> Result<PathBuf, CliError> {
+    let manifest_path = std::path::absolute(manifest_path).map_err(CliError::CurrentDir)?;
+    manifest_path
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.is_file())
+        .ok_or(CliError::NoLockfile(manifest_path, true))
+}
+
+/// Ask `question` on stderr and return whether the user answered yes
+fn confirm(question: &str) -> io::Result<bool> {
+    let mut stderr = io::stderr();
+    stderr.write_all(question.as_bytes())?;
+    stderr.flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Copy the lockfile to `backup_path` so that the downgrade can be undone with `restore`
+fn backup_lockfile(lock_path: &Path, backup_path: &Path, force: bool) -> Result<(), CliError> {
+    if backup_path.exists() && !force {
+        return Err(CliError::BackupExists(backup_path.to_owned()));
+    }
+    fs::copy(lock_path, backup_path)
+        .map_err(|err| CliError::Backup(backup_path.to_owned(), err))?;
+    log::info!(
+        "backed up {} to {}",
+        lock_path.display(),
+        backup_path.display()
+    );
+    Ok(())
+}
+
+/// The manifest --apply-toml pins the crates in: the one given with --manifest-path, which for a workspace
+/// member is not the one next to `lock_path`, or else the one next to `lock_path`
+fn manifest_to_pin(args: &CliArguments, lock_path: &Path) -> PathBuf {
+    args.manifest_path
+        .clone()
+        .unwrap_or_else(|| lock_path.with_file_name("Cargo.toml"))
+}
+
+/// Pin the direct dependencies in the manifest at `manifest_path` and return the packages that are not
+/// direct dependencies
+fn apply_to_manifest<'p>(
+    manifest_path: &Path,
+    packages: &'p [Package],
+) -> Result<Vec<&'p Package>, CliError> {
+    let manifest = fs::read_to_string(manifest_path)
+        .map_err(|err| CliError::ReadManifest(manifest_path.to_owned(), err))?;
+    let (pinned, remaining) = cargo_downgrade::pin_direct_dependencies(&manifest, packages)?;
+    fs::write(manifest_path, pinned)
+        .map_err(|err| CliError::WriteManifest(manifest_path.to_owned(), err))?;
+    log::info!(
+        "pinned {} direct dependencies in {}",
+        packages.len() - remaining.len(),
+        manifest_path.display()
+    );
+    Ok(remaining)
+}
+
+/// Write `content` to a temporary file next to `path` and move it into place, so that `path` is
+/// never left half-written
+fn write_atomically(
+    path: &Path,
+    content: &str,
+    error: fn(PathBuf, io::Error) -> CliError,
+) -> Result<(), CliError> {
+    let mut tmp_path = path.to_owned().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    fs::write(&tmp_path, content)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|err| {
+            let _ = fs::remove_file(&tmp_path);
+            error(path.to_owned(), err)
+        })
+}
+
+/// Move the backup made by `backup_lockfile` back into place
+fn restore_lockfile(backup_path: &Path, lock_path: &Path) -> Result<(), CliError> {
+    fs::copy(backup_path, lock_path)
+        .and_then(|_| fs::remove_file(backup_path))
+        .map_err(|err| CliError::Restore(backup_path.to_owned(), err))?;
+    log::info!(
+        "restored {} from {}",
+        lock_path.display(),
+        backup_path.display()
+    );
+    Ok(())
+}
+
+async fn run(mut args: CliArguments) -> Result<(), CliError> {
+    let started = Instant::now();
+    if let DowngradeModes::Completions { shell } = args.modes {
+        // cargo does not complete the options of external subcommands, so the binary itself is completed
+        let mut stdout = io::stdout();
+        if shell == clap_complete::Shell::Bash {
+            // the bash script of clap_complete does not find the subcommands of a binary whose name contains
+            // a -, so it is generated for cargo_downgrade and registered for cargo-downgrade as well
+            clap_complete::generate(
+                shell,
+                &mut CliArguments::command(),
+                "cargo_downgrade",
+                &mut stdout,
+            );
+            return writeln!(
+                stdout,
+                "complete -F _cargo_downgrade -o bashdefault -o default cargo-downgrade"
+            )
+            .map_err(CliError::Output);
+        }
+        clap_complete::generate(
+            shell,
+            &mut CliArguments::command(),
+            "cargo-downgrade",
+            &mut stdout,
+        );
+        return Ok(());
+    }
+    if let DowngradeModes::Man { output } = &args.modes {
+        let mut page = vec![];
+        // the version and description are only shown in the man page, not in --help
+        let command = CliArguments::command()
+            .version(env!("CARGO_PKG_VERSION"))
+            .about(env!("CARGO_PKG_DESCRIPTION"));
+        clap_mangen::Man::new(command)
+            .render(&mut page)
+            .map_err(CliError::Output)?;
+        let page = String::from_utf8_lossy(&page);
+        return match output {
+            Some(path) => write_atomically(path, &page, CliError::WriteOutput),
+            None => io::stdout()
+                .write_all(page.as_bytes())
+                .map_err(CliError::Output),
+        };
+    }
+    let lock_path = match (args.cargo_lock.take(), &args.manifest_path) {
+        (Some(path), _) => path,
+        (None, Some(manifest_path)) => find_lockfile(manifest_path)?,
+        (None, None) => {
+            let mut path = std::env::current_dir().map_err(CliError::CurrentDir)?;
             path.push("Cargo.lock");
             path
         }
     };
+    let backup_path = args.backup_path.take().unwrap_or_else(|| {
+        let mut path = lock_path.clone().into_os_string();
+        path.push(".bak");
+        PathBuf::from(path)
+    });
+
+    // restoring is the way out of a failed downgrade, so a broken config file must not get in its way
+    if let DowngradeModes::Restore = args.modes {
+        return restore_lockfile(&backup_path, &lock_path);
+    }
+
+    let config = load_config(args.config.as_deref(), &lock_path)?;
+    let source_date_epoch = source_date_epoch();
+    let format = apply_config(&mut args, config, &source_date_epoch);
+
+    if let DowngradeModes::Date = args.modes {
+        let datetime = resolve_date(&args, source_date_epoch)?;
+        println!("{}", datetime.to_rfc3339_opts(SecondsFormat::Secs, true));
+        return Ok(());
+    }
+    if let DowngradeModes::Query { crate_name } = &args.modes {
+        let datetime = resolve_date(&args, source_date_epoch)?;
+        return query(crate_name, datetime, &args, format, &lock_path).await;
+    }
+
+    let cargo_lock = cargo_downgrade::load_lockfile(&lock_path).map_err(|err| match err {
+        // running in a directory that is not the project root is the most common mistake
+        cargo_downgrade::Error::ReadCargoLock(err) if err.kind() == io::ErrorKind::NotFound => {
+            let dir = lock_path.parent().filter(|dir| !dir.as_os_str().is_empty());
+            CliError::NoLockfile(
+                dir.unwrap_or(Path::new(".")).to_owned(),
+                args.manifest_path.is_some(),
+            )
+        }
+        err => CliError::LoadLockfile(lock_path.clone(), err),
+    })?;
+    let dependency_tree = cargo_lock
+        .dependency_tree()
+        .map_err(CliError::DependencyTree)?;
+
+    let kinds: HashSet<DependencyKind> = if args.no_dev {
+        HashSet::from([DependencyKind::Normal, DependencyKind::Build])
+    } else {
+        args.kinds.iter().copied().collect()
+    };
+    // every edge is followed anyway if all kinds are selected
+    let workspace_kinds = match (kinds.len(), args.dev_date) {
+        (0 | 3, None) => None,
+        _ => Some(get_workspace_kinds(&lock_path)?),
+    };
+    if let (Some(dev_date), Some(workspace)) = (args.dev_date, &workspace_kinds) {
+        let dev_only = cargo_downgrade::get_dev_only_dependencies(&dependency_tree, workspace);
+        let mut names: Vec<&str> = dev_only.iter().copied().collect();
+        names.sort();
+        log::info!(
+            "only reachable through dev-dependencies: {}",
+            names.join(", ")
+        );
+        // dates given with --crate-date come later and take precedence
+        let crate_dates = std::mem::take(&mut args.crate_date);
+        args.crate_date = names
+            .into_iter()
+            .map(|name| (name.to_owned(), dev_date))
+            .chain(crate_dates)
+            .collect();
+    }
+    let kind_filter = match kinds.len() {
+        0 | 3 => None,
+        _ => workspace_kinds.map(|workspace| KindFilter { kinds, workspace }),
+    };
 
-    let cargo_lock = cargo_lock::Lockfile::load(lock_path).expect("unable to open cargo.lock");
-    let dependency_tree = cargo_lock.dependency_tree().unwrap();
+    if let DowngradeModes::This {
+        crates,
+        crates_file,
+    } = &mut args.modes
+    {
+        if let Some(path) = crates_file {
+            let content = fs::read_to_string(&*path)
+                .map_err(|err| CliError::ReadCratesFile(path.clone(), err))?;
+            crates.extend(parse_crate_list(&content));
+        }
+        if crates.iter().any(|name| name == "-") {
+            crates.retain(|name| name != "-");
+            let content = io::read_to_string(io::stdin()).map_err(CliError::ReadStdin)?;
+            crates.extend(parse_crate_list(&content));
+        }
+        // a typo in a name would otherwise only show up as a crate that is not found
+        for name in crates.iter() {
+            cargo_downgrade::validate_crate_name(name)?;
+        }
+    }
 
-    let crate_names = match &args.modes {
-        DowngradeModes::All { dependency_level } => {
-            cargo_downgrade::get_dependencies(*dependency_level, &dependency_tree)
+    let mut crate_names = match &args.modes {
+        DowngradeModes::All {
+            direct_only: true, ..
+        } => cargo_downgrade::get_direct_dependencies(&dependency_tree, kind_filter.as_ref())
+            .into_iter()
+            .collect(),
+        DowngradeModes::All {
+            dependency_level: Some(dependency_level),
+            cumulative: true,
+            include_roots,
+            ..
+        } => cargo_downgrade::get_dependency_levels(
+            None,
+            &dependency_tree,
+            kind_filter.as_ref(),
+            *include_roots,
+        )
+        .into_iter()
+        .filter(|&(_, level)| level <= dependency_level.get())
+        .map(|(name, _)| name)
+        .collect(),
+        DowngradeModes::All {
+            dependency_level,
+            include_roots,
+            ..
+        } => cargo_downgrade::get_dependencies(
+            *dependency_level,
+            &dependency_tree,
+            kind_filter.as_ref(),
+            *include_roots,
+        )
+        .into_iter()
+        .collect(),
+        DowngradeModes::Bisect { .. } => {
+            cargo_downgrade::get_dependencies(None, &dependency_tree, kind_filter.as_ref(), false)
                 .into_iter()
                 .collect()
         }
-        DowngradeModes::This { crates } => {
-            let mut crate_names = crates.iter().map(|s| s.as_str()).collect::<Vec<&str>>();
-            // vector has to be sorted for dedup to work
-            crate_names.sort();
-            crate_names.dedup();
-            crate_names
-        }
+        // use the spelling of the lockfile, so that foo_bar and foo-bar are deduplicated and looked up
+        DowngradeModes::This { crates, .. } => crates
+            .iter()
+            .map(|name| cargo_downgrade::canonical_crate_name(&cargo_lock, name))
+            .collect::<Vec<&str>>(),
+        DowngradeModes::Restore
+        | DowngradeModes::Date
+        | DowngradeModes::Query { .. }
+        | DowngradeModes::Completions { .. }
+        | DowngradeModes::Man { .. } => unreachable!("handled above"),
     };
+    // vector has to be sorted for dedup to work
+    crate_names.sort();
+    crate_names.dedup();
 
-    let datetime = if args.group.git {
-        get_timestamp_from_git().unwrap()
-    } else {
-        DateTime::parse_from_rfc2822(&args.group.date.unwrap())
-            .unwrap()
-            .with_timezone(&chrono::Utc)
-    };
-
-    // cargo update -p <package_name> --precise <version>
-
-    match cargo_downgrade::get_downgraded_dependencies(&crate_names, datetime).await {
-        Ok(downgraded_dependencies) => {
-            for dep in downgraded_dependencies {
-                if args.run {
-                    let output = Command::new("cargo")
-                        .arg("update")
-                        .arg("-p")
-                        .arg(dep.name)
-                        .arg("--precise")
-                        .arg(dep.version)
-                        .output()
-                        .unwrap();
-
-                    io::stdout().write_all(&output.stdout).unwrap();
-                    io::stderr().write_all(&output.stderr).unwrap();
-                } else {
-                    println!("{}", dep);
+    if !args.exclude.is_empty() {
+        let (excluded, included): (Vec<&str>, Vec<&str>) =
+            crate_names.into_iter().partition(|name| {
+                args.exclude
+                    .iter()
+                    .any(|exclude| cargo_downgrade::same_crate_name(exclude, name))
+            });
+        log::info!("excluding crates: {}", excluded.join(", "));
+        crate_names = included;
+    }
+
+    if !args.include.is_empty() {
+        crate_names.retain(|name| {
+            args.include
+                .iter()
+                .any(|pattern| cargo_downgrade::glob_match(pattern, name))
+        });
+    }
+
+    if args.list_deps {
+        let levels = cargo_downgrade::get_dependency_levels(
+            None,
+            &dependency_tree,
+            kind_filter.as_ref(),
+            true,
+        );
+        let listed: Vec<_> = crate_names
+            .iter()
+            .map(|&name| ListedDependency {
+                name,
+                level: levels.get(name).copied(),
+            })
+            .collect();
+        let json = serde_json::to_string_pretty(&listed).map_err(CliError::Serialize)? + "\n";
+        match &args.output {
+            Some(path) => write_atomically(path, &json, CliError::WriteOutput)?,
+            None => print!("{}", json),
+        }
+        return Ok(());
+    }
+
+    if let Some(TreeFormat::Dot) = args.print_tree {
+        let crate_names = crate_names.iter().copied().collect();
+        let dot = cargo_downgrade::to_dot(&dependency_tree, &crate_names);
+        match &args.output {
+            Some(path) => write_atomically(path, &dot, CliError::WriteOutput)?,
+            None => print!("{}", dot),
+        }
+        return Ok(());
+    }
+
+    let dependencies: Vec<Dependency> = crate_names
+        .iter()
+        .flat_map(|name| Dependency::all_from_lockfile(&cargo_lock, name))
+        .collect();
+    // path and git dependencies cannot be looked up in a registry
+    let (dependencies, skipped): (Vec<Dependency>, Vec<Dependency>) = dependencies
+        .into_iter()
+        .partition(Dependency::is_from_registry);
+    if !skipped.is_empty() {
+        log::info!(
+            "skipped (not on crates.io): {}",
+            skipped
+                .iter()
+                .map(|dependency| dependency.name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    let dependencies = match &args.source {
+        Some(source) => {
+            let (included, other): (Vec<Dependency>, Vec<Dependency>) = dependencies
+                .into_iter()
+                .partition(|dependency| dependency.is_from_source(source.as_str()));
+            if !other.is_empty() {
+                log::info!(
+                    "excluding crates from other sources than {}: {}",
+                    source,
+                    other
+                        .iter()
+                        .map(|dependency| dependency.name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+            included
+        }
+        None => dependencies,
+    };
+    if let DowngradeModes::Bisect { good, bad, command } = &args.modes {
+        let bisection = Bisection {
+            args: &args,
+            format,
+            dependencies: &dependencies,
+            lock_path: &lock_path,
+            command,
+        };
+        return bisection.run(*good, *bad).await;
+    }
+    let (report, datetime) = match &args.group.to_lockfile {
+        Some(path) => {
+            let snapshot = cargo_downgrade::load_lockfile(path)
+                .map_err(|err| CliError::LoadLockfile(path.clone(), err))?;
+            (
+                cargo_downgrade::versions_from_snapshot(&dependencies, &snapshot),
+                None,
+            )
+        }
+        None => {
+            let datetime = resolve_date(&args, source_date_epoch)?;
+            let report = cargo_downgrade::get_downgraded_dependencies(
+                &dependencies,
+                datetime,
+                &select_options(&args),
+                &fetch_options(&args, format, &lock_path),
+            )
+            .await?;
+            (report, Some(datetime))
+        }
+    };
+    if !report.already_old.is_empty() {
+        log::info!("already old, skipped: {}", report.already_old.join(", "));
+    }
+    let downgraded_dependencies = report.resolved;
+    // crates that are already locked to their target version do not need a cargo update, unless forced
+    let (unchanged, mut changed): (Vec<&Package>, Vec<&Package>) = downgraded_dependencies
+        .iter()
+        .partition(|package| !args.force && package.locked.as_ref() == Some(&package.version));
+    let state_path = resume_state_path(&lock_path);
+    let resumed = match args.resume {
+        true => ResumeState::load(&state_path)?,
+        false => None,
+    };
+    if let Some(state) = &resumed {
+        if state.date != datetime {
+            log::warn!(
+                "the interrupted run downgraded to {}, continuing with {}",
+                state
+                    .date
+                    .map_or("another Cargo.lock".to_owned(), |date| date.to_string()),
+                datetime.map_or("another Cargo.lock".to_owned(), |date| date.to_string())
+            );
+        }
+        state.skip_applied(&mut changed);
+        log::info!(
+            "resuming, {} crates have already been downgraded",
+            state.applied.len()
+        );
+    } else if args.resume {
+        log::info!("no interrupted run to resume, starting from scratch");
+    } else if args.run && state_path.exists() {
+        log::warn!(
+            "an earlier run has been interrupted, pass --resume to continue it or remove {}",
+            state_path.display()
+        );
+    }
+    if args.apply_toml {
+        let remaining = apply_to_manifest(
+            &manifest_to_pin(&args, &lock_path),
+            &downgraded_dependencies,
+        )?;
+        if !remaining.is_empty() {
+            log::info!(
+                "{} crates are not direct dependencies, add this to Cargo.toml to pin them:",
+                remaining.len()
+            );
+            let remaining: Vec<_> = remaining.into_iter().cloned().collect();
+            let table = cargo_downgrade::to_patch_table(&remaining);
+            // keep stdout reserved for the machine-readable plan
+            if !format.is_text() {
+                io::stderr().write_all(table.as_bytes())
+            } else {
+                io::stdout().write_all(table.as_bytes())
+            }
+            .map_err(CliError::Output)?;
+        }
+    } else if !args.run {
+        log::info!("dry run: pass --run or --apply-toml to apply");
+    } else if !changed.is_empty() {
+        // only ask when someone can answer, scripts and CI pass --yes or have no terminal anyway
+        let interactive = io::stdin().is_terminal() && std::env::var_os("CI").is_none();
+        if interactive && !args.yes {
+            let question = format!(
+                "This will downgrade {} crates. Continue? [y/N] ",
+                changed.len()
+            );
+            if !confirm(&question).map_err(CliError::Prompt)? {
+                log::info!("aborted, {} has not been changed", lock_path.display());
+                return Ok(());
+            }
+        }
+        // the backup of the interrupted run still has the original Cargo.lock
+        if resumed.is_none() {
+            backup_lockfile(&lock_path, &backup_path, args.force)?;
+        }
+    }
+    let manifest_path = lock_path.with_file_name("Cargo.toml");
+    // the crates downgraded in this run, without those an interrupted run has already downgraded
+    let (downgraded, update_failed) = if args.run && args.edit_lockfile {
+        log::warn!(
+            "editing {} directly, the versions are not checked against the requirements in Cargo.toml",
+            lock_path.display()
+        );
+        let changed: Vec<Package> = changed.iter().map(|&dep| dep.clone()).collect();
+        let mut lockfile = cargo_lock.clone();
+        let remaining = cargo_downgrade::downgrade_lockfile(&mut lockfile, &changed)?;
+        write_atomically(&lock_path, &lockfile.to_string(), CliError::WriteLockfile)?;
+        let failed = remaining
+            .into_iter()
+            .map(|dep| (dep.clone(), "not locked in Cargo.lock".to_owned()))
+            .collect::<Vec<_>>();
+        (changed.len() - failed.len(), failed)
+    } else if args.run {
+        let manifest_dir = manifest_path.parent().unwrap_or(Path::new(""));
+        let mut state = resumed.unwrap_or(ResumeState {
+            date: datetime,
+            applied: vec![],
+        });
+        state.store(&state_path)?;
+        let mut downgraded = 0;
+        let mut failed = vec![];
+        catch_interrupts().await;
+        // one crate at a time, so that the state file tells which crates are done if the run dies
+        for &package in &changed {
+            match cargo_downgrade::apply_downgrades(
+                std::slice::from_ref(package),
+                manifest_dir,
+                &INTERRUPTED,
+            ) {
+                Ok(report) if report.failed.is_empty() => {
+                    downgraded += 1;
+                    state.applied.push(ResumeState::key(package));
+                    state.store(&state_path)?;
+                }
+                Ok(report) => failed.extend(report.failed),
+                Err(cargo_downgrade::Error::Interrupted) if args.resume => {
+                    return Err(CliError::Paused(lock_path));
                 }
+                // a half-downgraded Cargo.lock is of no use
+                Err(cargo_downgrade::Error::Interrupted) => {
+                    restore_lockfile(&backup_path, &lock_path)?;
+                    ResumeState::remove(&state_path);
+                    return Err(CliError::Interrupted(lock_path));
+                }
+                Err(cargo_downgrade::Error::CargoUpdate(err)) => {
+                    return Err(missing_program(
+                        err,
+                        "cargo",
+                        "install Rust or leave out --run to only print the plan",
+                        CliError::CargoUpdate,
+                    ));
+                }
+                Err(err) => return Err(err.into()),
             }
         }
+        ResumeState::remove(&state_path);
+        (downgraded, failed)
+    } else {
+        (changed.len(), vec![])
+    };
+
+    let stdout_colors = Colors {
+        enabled: colors_wanted(args.no_color)
+            && args.output.is_none()
+            && io::stdout().is_terminal(),
+    };
+    let stderr_colors = Colors {
+        // escape codes would end up in the messages of JSON logs
+        enabled: colors_wanted(args.no_color)
+            && io::stderr().is_terminal()
+            && args.log_format == LogFormat::Text,
+    };
+    let plan = match format {
+        _ if args.print_commands => changed
+            .iter()
+            .map(|dep| {
+                let command: Vec<_> = std::iter::once("cargo".into())
+                    .chain(cargo_downgrade::cargo_update_arguments(dep, &manifest_path))
+                    .map(|argument| {
+                        shlex::try_quote(&argument.to_string_lossy()).map(Cow::into_owned)
+                    })
+                    .collect::<Result<_, _>>()
+                    .map_err(CliError::Quote)?;
+                Ok(command.join(" ") + "\n")
+            })
+            .collect::<Result<String, CliError>>()?,
+        OutputFormat::Text | OutputFormat::Github => downgraded_dependencies
+            .iter()
+            .map(|dep| {
+                format!(
+                    "{} = \"={}\"\n",
+                    stdout_colors.crate_name(&dep.name),
+                    stdout_colors.version(&dep.version)
+                )
+            })
+            .collect(),
+        OutputFormat::Json => {
+            serde_json::to_string_pretty(&downgraded_dependencies).map_err(CliError::Serialize)?
+                + "\n"
+        }
+        OutputFormat::Patch => cargo_downgrade::to_patch_table(&downgraded_dependencies),
+    };
+    match &args.output {
+        Some(path) => {
+            write_atomically(path, &plan, CliError::WriteOutput)?;
+            log::info!("wrote the downgrade plan to {}", path.display());
+        }
+        // the output of cargo update already shows what has been downgraded, editing Cargo.lock shows nothing
+        None if args.run && !args.edit_lockfile && format.is_text() => {}
+        None => print!("{}", plan),
+    }
+
+    // the JSON plan has the locked version of every entry, the table is for humans
+    if format.is_text() && !changed.is_empty() {
+        eprint!("{}", diff_table(&changed, stderr_colors));
+    }
+
+    log::info!(
+        "{} {}, already at target {}, already old {}, skipped {} (not on crates.io), not found {}, failed {} (no appropriate version), failed {} (cargo update)",
+        if args.run || args.apply_toml {
+            "downgraded"
+        } else {
+            "would downgrade"
+        },
+        downgraded,
+        unchanged.len(),
+        report.already_old.len(),
+        stderr_colors.skipped(skipped.len() + report.skipped.len()),
+        stderr_colors.failed(report.not_found.len()),
+        stderr_colors.failed(report.failed.len()),
+        stderr_colors.failed(update_failed.len())
+    );
+    if args.stats {
+        let stats = &report.stats;
+        eprintln!(
+            "{} requests ({} retried), {} cache hits, fetched in {:.1}s, {:.1}s in total",
+            stats.requests,
+            stats.retries,
+            stats.cache_hits,
+            stats.elapsed.as_secs_f64(),
+            started.elapsed().as_secs_f64()
+        );
+    }
+    if format == OutputFormat::Github {
+        let skipped = skipped
+            .iter()
+            .map(|dependency| dependency.name)
+            .chain(report.skipped.iter().map(String::as_str));
+        for name in skipped {
+            println!(
+                "{}",
+                github_annotation(
+                    "warning",
+                    &format!("{} skipped", name),
+                    &format!("{} is not on crates.io and has not been downgraded", name)
+                )
+            );
+        }
+        for crate_name in &report.not_found {
+            println!(
+                "{}",
+                github_annotation(
+                    "error",
+                    &format!("{} not found", crate_name),
+                    &format!(
+                        "{} does not exist in the registry, check the name",
+                        crate_name
+                    )
+                )
+            );
+        }
+        for (crate_name, reason) in &report.failed {
+            println!(
+                "{}",
+                github_annotation(
+                    "error",
+                    &format!("No appropriate version of {}", crate_name),
+                    &reason.to_string()
+                )
+            );
+        }
+        for (dep, reason) in &update_failed {
+            println!(
+                "{}",
+                github_annotation(
+                    "error",
+                    &format!("cargo update failed for {}", dep.name),
+                    reason
+                )
+            );
+        }
+    }
+    if !update_failed.is_empty() {
+        log::error!("cargo update failed for {} crates:", update_failed.len());
+        for (dep, reason) in &update_failed {
+            log::error!("  {}: {}", stderr_colors.failed(&dep.name), reason);
+        }
+        if update_failed
+            .iter()
+            .any(|(_, reason)| reason.contains("failed to select a version for the requirement"))
+        {
+            log::warn!(
+                "a version requirement in Cargo.toml does not allow the downgrade, relax it or pass --apply-toml to pin the direct dependencies"
+            );
+        }
+    }
+    if !report.not_found.is_empty() {
+        log::error!(
+            "{} crates not found in the registry, check the names: {}",
+            report.not_found.len(),
+            report
+                .not_found
+                .iter()
+                .map(|name| stderr_colors.failed(name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if !report.failed.is_empty() {
+        log::error!(
+            "no appropriate version found for {} crates:",
+            report.failed.len()
+        );
+        for (crate_name, reason) in &report.failed {
+            log::error!("  {}: {}", stderr_colors.failed(crate_name), reason);
+        }
+    }
+    if !report.failed.is_empty() || !report.not_found.is_empty() {
+        return Err(cargo_downgrade::Error::NoAppropriateVersion.into());
+    }
+    if !update_failed.is_empty() {
+        return Err(CliError::UpdateFailed(update_failed.len()));
+    }
+
+    Ok(())
+}
+
+/// Whether colors have not been turned off with --no-color or NO_COLOR, see <https://no-color.org/>
+fn colors_wanted(no_color: bool) -> bool {
+    !no_color && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+/// ANSI styles for the human-readable output, which leave the text as is if disabled
+#[derive(Debug, Clone, Copy)]
+struct Colors {
+    enabled: bool,
+}
+
+impl Colors {
+    fn paint(self, code: &str, text: impl fmt::Display) -> String {
+        if self.enabled {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn crate_name(self, text: impl fmt::Display) -> String {
+        self.paint("1", text)
+    }
+
+    fn version(self, text: impl fmt::Display) -> String {
+        self.paint("32", text)
+    }
+
+    fn skipped(self, text: impl fmt::Display) -> String {
+        self.paint("33", text)
+    }
+
+    fn failed(self, text: impl fmt::Display) -> String {
+        self.paint("31", text)
+    }
+}
+
+/// Table with the locked and the downgraded version of each crate in `changed`
+fn diff_table(changed: &[&Package], colors: Colors) -> String {
+    let locked = |dep: &Package| dep.locked.clone().unwrap_or_else(|| "?".to_owned());
+    let name_width = changed.iter().map(|dep| dep.name.len()).max().unwrap_or(0);
+    let locked_width = changed
+        .iter()
+        .map(|dep| locked(dep).len())
+        .max()
+        .unwrap_or(0);
+    changed
+        .iter()
+        .map(|dep| {
+            // pad before painting, the escape codes would count towards the width otherwise
+            format!(
+                "{}  {:>width$} -> {}\n",
+                colors.crate_name(format!("{:<width$}", dep.name, width = name_width)),
+                locked(dep),
+                colors.version(&dep.version),
+                width = locked_width
+            )
+        })
+        .collect()
+}
+
+/// A GitHub Actions workflow command that annotates the run with `message`,
+/// see <https://docs.github.com/en/actions/reference/workflow-commands-for-github-actions>
+fn github_annotation(level: &str, title: &str, message: &str) -> String {
+    let escape = |text: &str| {
+        text.replace('%', "%25")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A")
+    };
+    format!(
+        "::{} title={}::{}",
+        level,
+        escape(title).replace(':', "%3A").replace(',', "%2C"),
+        escape(message)
+    )
+}
+
+/// Parse the command line, which cargo passes as `cargo-downgrade downgrade ...` when run as `cargo downgrade ...`
+fn parse_args(args: impl IntoIterator<Item = OsString>) -> CliArguments {
+    let mut args: Vec<OsString> = args.into_iter().collect();
+    if args.get(1).is_some_and(|arg| arg == "downgrade") {
+        args.remove(1);
+    }
+    CliArguments::parse_from(args)
+}
+
+/// The log level for the number of -v and -q flags, starting from info
+fn log_level(verbose: u8, quiet: u8) -> log::LevelFilter {
+    match i16::from(verbose) - i16::from(quiet) {
+        ..=-2 => log::LevelFilter::Error,
+        -1 => log::LevelFilter::Warn,
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        2.. => log::LevelFilter::Trace,
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = parse_args(args_os());
+
+    let log_format = args.log_format;
+    match log_format {
+        LogFormat::Text => simple_logger::SimpleLogger::new()
+            .with_level(log_level(args.verbose, args.quiet))
+            .with_colors(colors_wanted(args.no_color))
+            .env()
+            .init(),
+        LogFormat::Json => json_log::JsonLogger::new(log_level(args.verbose, args.quiet)).init(),
+    }
+    .unwrap();
+
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
-            eprintln!("Error: {}", Report::new(err));
-            std::process::exit(1);
+            let code = err.exit_code();
+            match log_format {
+                LogFormat::Text => eprintln!("Error: {}", Report::new(err)),
+                LogFormat::Json => log::error!("{}", Report::new(err)),
+            }
+            code
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_args_as_cargo_subcommand() {
+        let standalone =
+            parse_args(["cargo-downgrade", "-d", "2021-02-22", "all"].map(OsString::from));
+        let subcommand = parse_args(
+            ["cargo-downgrade", "downgrade", "-d", "2021-02-22", "all"].map(OsString::from),
+        );
+        assert_eq!(format!("{:?}", standalone), format!("{:?}", subcommand));
+        assert_eq!(standalone.group.date.as_deref(), Some("2021-02-22"));
+    }
+
+    #[test]
+    fn test_parse_crate_list() {
+        let content = "# pinned by the security review\nserde, serde_json\n\n  log # logging\nrand,\ntokio mio\n";
+        assert_eq!(
+            parse_crate_list(content).collect::<Vec<_>>(),
+            ["serde", "serde_json", "log", "rand", "tokio", "mio"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_rejects_invalid_crate_name() {
+        let args = parse_args(["cargo-downgrade", "query", "../x"].map(OsString::from));
+        let date = cargo_downgrade::parse_date("2021-02-22").unwrap();
+        let result = query(
+            "../x",
+            date,
+            &args,
+            OutputFormat::Text,
+            Path::new("Cargo.lock"),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(CliError::Downgrade(cargo_downgrade::Error::InvalidCrateName(name, _))) if name == "../x"
+        ));
+    }
+
+    #[test]
+    fn test_apply_to_workspace_member() {
+        let dir = tempfile::tempdir().unwrap();
+        let root_manifest = "[workspace]\nmembers = [\"member\"]\n";
+        fs::write(dir.path().join("Cargo.toml"), root_manifest).unwrap();
+        fs::write(dir.path().join("Cargo.lock"), "version = 3\n").unwrap();
+        fs::create_dir(dir.path().join("member")).unwrap();
+        let member_path = dir.path().join("member/Cargo.toml");
+        fs::write(
+            &member_path,
+            "[package]\nname = \"member\"\n\n[dependencies]\nserde = \"1.0\"\n",
+        )
+        .unwrap();
+
+        let args = parse_args([
+            OsString::from("cargo-downgrade"),
+            OsString::from("--manifest-path"),
+            member_path.clone().into_os_string(),
+            OsString::from("all"),
+        ]);
+        // the lockfile of the workspace is in the root, the crates are still pinned in the member
+        let lock_path = find_lockfile(&member_path).unwrap();
+        assert_eq!(lock_path, dir.path().join("Cargo.lock"));
+        let manifest_path = manifest_to_pin(&args, &lock_path);
+        assert_eq!(manifest_path, member_path);
+
+        let packages = [Package {
+            name: "serde".to_owned(),
+            version: "1.0.123".to_owned(),
+            locked: Some("1.0.124".to_owned()),
+            checksum: None,
+        }];
+        assert!(
+            apply_to_manifest(&manifest_path, &packages)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            fs::read_to_string(&member_path)
+                .unwrap()
+                .contains("serde = \"=1.0.123\"")
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("Cargo.toml")).unwrap(),
+            root_manifest
+        );
+    }
+
+    #[test]
+    fn test_config_precedence() {
+        let config = || -> Config {
+            toml::from_str(
+                "date = \"2021-01-01\"\nexclude = [\"log\"]\nformat = \"json\"\n\n[floor]\nserde = \"1.0.100\"\nrand = \"0.8.0\"\n",
+            )
+            .unwrap()
+        };
+        let epoch = DateTime::from_timestamp(1_600_000_000, 0).unwrap();
+
+        // the file fills in what is not given on the command line
+        let mut args = parse_args(["cargo-downgrade", "all"].map(OsString::from));
+        assert_eq!(
+            apply_config(&mut args, config(), &Ok(None)),
+            OutputFormat::Json
+        );
+        assert_eq!(args.group.date.as_deref(), Some("2021-01-01"));
+        assert_eq!(args.exclude, ["log"]);
+
+        // SOURCE_DATE_EPOCH wins over the file
+        let mut args = parse_args(["cargo-downgrade", "all"].map(OsString::from));
+        apply_config(&mut args, config(), &Ok(Some(epoch)));
+        assert_eq!(args.group.date, None);
+        assert_eq!(resolve_date(&args, Ok(Some(epoch))).unwrap(), epoch);
+
+        // the command line wins over both, --floor only overrides the floor of its crate
+        let mut args = parse_args(
+            [
+                "cargo-downgrade",
+                "-d",
+                "2021-02-22",
+                "--exclude",
+                "rand",
+                "--floor",
+                "serde@1.0.120",
+                "--format",
+                "text",
+                "all",
+            ]
+            .map(OsString::from),
+        );
+        assert_eq!(
+            apply_config(&mut args, config(), &Ok(Some(epoch))),
+            OutputFormat::Text
+        );
+        assert_eq!(args.group.date.as_deref(), Some("2021-02-22"));
+        assert_eq!(args.exclude, ["rand"]);
+        let floors = select_options(&args).floors;
+        assert_eq!(floors["serde"], semver::Version::new(1, 0, 120));
+        assert_eq!(floors["rand"], semver::Version::new(0, 8, 0));
+
+        // - and _ are the same in the names of the floors
+        let config = toml::from_str("[floor]\nserde_json = \"1.0.50\"\n").unwrap();
+        let mut args = parse_args(
+            ["cargo-downgrade", "--floor", "serde-json@1.0.60", "all"].map(OsString::from),
+        );
+        apply_config(&mut args, config, &Ok(None));
+        let floors = select_options(&args).floors;
+        assert_eq!(floors.len(), 1);
+        assert_eq!(floors["serde_json"], semver::Version::new(1, 0, 60));
+    }
+
+    #[test]
+    fn test_resume_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = resume_state_path(&dir.path().join("Cargo.lock"));
+        assert!(ResumeState::load(&path).unwrap().is_none());
+
+        let package = |name: &str, version: &str| Package {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            locked: None,
+            checksum: None,
+        };
+        let (serde, log, rand) = (
+            package("serde", "1.0.123"),
+            package("log", "0.4.14"),
+            package("rand", "0.8.3"),
+        );
+        let state = ResumeState {
+            date: Some(cargo_downgrade::parse_date("2021-02-22").unwrap()),
+            applied: vec![ResumeState::key(&serde), ResumeState::key(&log)],
+        };
+        state.store(&path).unwrap();
+
+        let loaded = ResumeState::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.date, state.date);
+        // a crate that has been downgraded to another version is not skipped
+        let other_log = package("log", "0.4.13");
+        let mut pending = vec![&serde, &other_log, &rand];
+        loaded.skip_applied(&mut pending);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].version, "0.4.13");
+        assert_eq!(pending[1].name, "rand");
+
+        ResumeState::remove(&path);
+        assert!(ResumeState::load(&path).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bisect_dates() {
+        // a new version of foo is published every day, the test command fails from the 7th on
+        let start = cargo_downgrade::parse_date("2021-01-01").unwrap();
+        let release = |day| start + TimeDelta::days(day);
+        let plan = async |date| {
+            let published = (1..=10).filter(|&day| release(day) < date).count();
+            Ok(vec![Package {
+                name: "foo".to_owned(),
+                version: format!("1.0.{}", published),
+                locked: Some("1.0.10".to_owned()),
+                checksum: None,
+            }])
+        };
+        let published = |plan: &[Package]| semver::Version::parse(&plan[0].version).unwrap().patch;
+        let mut runs = 0;
+        let window = bisect_dates(release(0), release(11), plan, |plan: &[Package]| {
+            runs += 1;
+            Ok(published(plan) < 7)
+        })
+        .await
+        .unwrap();
+        assert!(window.good <= release(7) && window.bad > release(7));
+        assert!(window.bad - window.good <= TimeDelta::seconds(1));
+        assert_eq!(window.good_plan[0].version, "1.0.6");
+        assert_eq!(window.bad_plan[0].version, "1.0.7");
+        // the test command only runs when the versions differ from both ends of the window
+        assert!(runs <= 2 + 4, "{} test runs", runs);
+
+        // with the bad date before the good one, the date at which the command starts to pass is found
+        let window = bisect_dates(release(11), release(0), plan, |plan: &[Package]| {
+            Ok(published(plan) >= 7)
+        })
+        .await
+        .unwrap();
+        assert!(window.bad <= release(7) && window.good > release(7));
+
+        let result = bisect_dates(release(0), release(11), plan, |_: &[Package]| Ok(false)).await;
+        assert!(matches!(
+            result,
+            Err(CliError::BisectEndpoint("fails", "good", _))
+        ));
+        let result = bisect_dates(release(0), release(11), plan, |_: &[Package]| Ok(true)).await;
+        assert!(matches!(
+            result,
+            Err(CliError::BisectEndpoint("passes", "bad", _))
+        ));
+        // nothing has been published between the dates, so there is nothing to bisect
+        let mut runs = 0;
+        let result = bisect_dates(
+            release(3) + TimeDelta::hours(1),
+            release(3) + TimeDelta::hours(12),
+            plan,
+            |_: &[Package]| {
+                runs += 1;
+                Ok(runs == 1)
+            },
+        )
+        .await;
+        assert!(matches!(result, Err(CliError::BisectSameVersions)));
+    }
+
+    #[test]
+    fn test_github_annotation() {
+        assert_eq!(
+            github_annotation("error", "failed: a, b", "100%\nsure"),
+            "::error title=failed%3A a%2C b::100%25%0Asure"
+        );
+    }
+
+    #[test]
+    fn test_completions() {
+        for shell in [
+            clap_complete::Shell::Bash,
+            clap_complete::Shell::Zsh,
+            clap_complete::Shell::Fish,
+            clap_complete::Shell::PowerShell,
+        ] {
+            let mut script = vec![];
+            clap_complete::generate(
+                shell,
+                &mut CliArguments::command(),
+                "cargo-downgrade",
+                &mut script,
+            );
+            assert!(!script.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_man_page() {
+        let mut page = vec![];
+        clap_mangen::Man::new(CliArguments::command())
+            .render(&mut page)
+            .unwrap();
+        assert!(
+            String::from_utf8(page)
+                .unwrap()
+                .contains(".TH cargo-downgrade 1")
+        );
+    }
+}
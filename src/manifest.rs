@@ -0,0 +1,210 @@
+use std::ops::Range;
+
+use toml::{
+    Spanned,
+    de::{DeTable, DeValue},
+};
+
+use crate::{Error, Package, Result};
+
+/// Tables of a manifest that list dependencies
+const DEPENDENCY_TABLES: [&str; 3] = ["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// Pin the direct dependencies in the Cargo.toml `manifest` to the exact versions in `packages`.
+///
+/// Only the version requirements are replaced, comments and formatting are kept. If a crate is locked in
+/// several versions, each requirement is pinned to the version it matches. Returns the edited manifest
+/// together with the packages that are not direct dependencies with a matching version requirement.
+pub fn pin_direct_dependencies<'p>(
+    manifest: &str,
+    packages: &'p [Package],
+) -> Result<(String, Vec<&'p Package>)> {
+    let root = DeTable::parse(manifest).map_err(Error::ParseManifest)?;
+
+    let mut requirements = vec![];
+    collect_requirements(root.get_ref(), &mut requirements);
+    if let Some(DeValue::Table(targets)) = get(root.get_ref(), "target") {
+        for (_, target) in targets.iter() {
+            if let DeValue::Table(target) = target.get_ref() {
+                collect_requirements(target, &mut requirements);
+            }
+        }
+    }
+
+    let mut edits = vec![];
+    let mut pinned_packages = vec![false; packages.len()];
+    for (name, requirement, span) in &requirements {
+        // e.g. rand 0.7 and rand 0.8, of which only one is the direct dependency with this requirement
+        let Some(index) = packages
+            .iter()
+            .position(|package| package.name == *name && satisfies(requirement, package))
+        else {
+            continue;
+        };
+        pinned_packages[index] = true;
+        edits.push((span.clone(), format!("\"={}\"", packages[index].version)));
+    }
+    let remaining = packages
+        .iter()
+        .zip(pinned_packages)
+        .filter(|(_, pinned)| !pinned)
+        .map(|(package, _)| package)
+        .collect();
+
+    edits.sort_by_key(|(span, _)| span.start);
+    let mut pinned = manifest.to_owned();
+    for (span, replacement) in edits.into_iter().rev() {
+        pinned.replace_range(span, &replacement);
+    }
+    Ok((pinned, remaining))
+}
+
+/// Whether `package` is the one that `requirement` resolved to: either its locked version, which cargo
+/// picked for the requirement, or its downgraded version matches. Unparsable requirements match anything.
+fn satisfies(requirement: &str, package: &Package) -> bool {
+    let Ok(requirement) = semver::VersionReq::parse(requirement) else {
+        return true;
+    };
+    [package.locked.as_deref(), Some(&package.version)]
+        .into_iter()
+        .flatten()
+        .filter_map(|version| semver::Version::parse(version).ok())
+        .any(|version| requirement.matches(&version))
+}
+
+/// Collect the crate names, version requirements and their spans in the dependency tables of `table`
+fn collect_requirements<'i>(
+    table: &'i DeTable<'i>,
+    requirements: &mut Vec<(&'i str, &'i str, Range<usize>)>,
+) {
+    for kind in DEPENDENCY_TABLES {
+        let Some(DeValue::Table(dependencies)) = get(table, kind) else {
+            continue;
+        };
+        for (key, value) in dependencies.iter() {
+            match value.get_ref() {
+                DeValue::String(requirement) => {
+                    requirements.push((key.get_ref(), requirement, value.span()))
+                }
+                DeValue::Table(detailed) => {
+                    let name = match get(detailed, "package") {
+                        Some(DeValue::String(package)) => package,
+                        _ => key.get_ref(),
+                    };
+                    if let Some(version) = get_spanned(detailed, "version")
+                        && let DeValue::String(requirement) = version.get_ref()
+                    {
+                        requirements.push((name, requirement, version.span()));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn get<'t, 'i>(table: &'t DeTable<'i>, key: &str) -> Option<&'t DeValue<'i>> {
+    get_spanned(table, key).map(Spanned::get_ref)
+}
+
+fn get_spanned<'t, 'i>(table: &'t DeTable<'i>, key: &str) -> Option<&'t Spanned<DeValue<'i>>> {
+    table
+        .iter()
+        .find(|(name, _)| name.get_ref() == key)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pin_direct_dependencies() {
+        let manifest = r#"[package]
+name = "example"
+version = "0.1.0"
+
+[dependencies]
+# keep this comment
+serde = { version = "1.0", features = ["derive"] }
+log = "0.4"   # and this one
+json = { package = "serde_json", version = '1' }
+local = { path = "../local" }
+
+[target.'cfg(unix)'.dev-dependencies]
+log = "0.4.1"
+"#;
+        let packages = [
+            Package {
+                name: "serde".to_owned(),
+                version: "1.0.123".to_owned(),
+                locked: None,
+                checksum: None,
+            },
+            Package {
+                name: "log".to_owned(),
+                version: "0.4.14".to_owned(),
+                locked: None,
+                checksum: None,
+            },
+            Package {
+                name: "serde_json".to_owned(),
+                version: "1.0.61".to_owned(),
+                locked: None,
+                checksum: None,
+            },
+            Package {
+                name: "memchr".to_owned(),
+                version: "2.3.4".to_owned(),
+                locked: None,
+                checksum: None,
+            },
+        ];
+
+        let (pinned, remaining) = pin_direct_dependencies(manifest, &packages).unwrap();
+        assert_eq!(
+            pinned,
+            r#"[package]
+name = "example"
+version = "0.1.0"
+
+[dependencies]
+# keep this comment
+serde = { version = "=1.0.123", features = ["derive"] }
+log = "=0.4.14"   # and this one
+json = { package = "serde_json", version = "=1.0.61" }
+local = { path = "../local" }
+
+[target.'cfg(unix)'.dev-dependencies]
+log = "=0.4.14"
+"#
+        );
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].name, "memchr");
+    }
+
+    #[test]
+    fn test_pin_crate_locked_in_several_versions() {
+        let manifest = "[dependencies]\nrand = \"0.8\"\n";
+        let packages = [
+            Package {
+                name: "rand".to_owned(),
+                version: "0.7.0".to_owned(),
+                locked: Some("0.7.3".to_owned()),
+                checksum: None,
+            },
+            Package {
+                name: "rand".to_owned(),
+                version: "0.8.0".to_owned(),
+                locked: Some("0.8.3".to_owned()),
+                checksum: None,
+            },
+        ];
+
+        // rand 0.7 is only a transitive dependency and is left to [patch.crates-io]
+        let (pinned, remaining) = pin_direct_dependencies(manifest, &packages).unwrap();
+        assert_eq!(pinned, "[dependencies]\nrand = \"=0.8.0\"\n");
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].version, "0.7.0");
+    }
+}
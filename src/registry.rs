@@ -0,0 +1,293 @@
+use std::{fmt, time::Duration};
+
+use crates_io_api::Version;
+use futures::{FutureExt, future::BoxFuture};
+use reqwest::{StatusCode, header};
+use serde::Deserialize;
+use tokio::{sync::Mutex, time::Instant};
+use url::Url;
+
+use crate::{Error, PublishedVersion, Result, VersionSource};
+
+/// Base URL of the crates.io web API
+pub const CRATES_IO_API: &str = "https://crates.io/api/v1/";
+
+/// Client for the crates.io compatible web API of a registry
+#[derive(Debug)]
+pub struct RegistryClient {
+    client: reqwest::Client,
+    base_url: Url,
+    rate_limit: Duration,
+    last_request: Mutex<Option<Instant>>,
+    /// Proxy the requests go through, to name it when it cannot be reached
+    proxy: Option<String>,
+}
+
+impl RegistryClient {
+    /// Client for the API at `base_url` that sends at most one request per `rate_limit` and gives up on
+    /// requests that take longer than `timeout`. Requests go through `proxy` if given, otherwise through the proxy in `HTTPS_PROXY` or `ALL_PROXY` if set.
+    pub fn new(
+        base_url: Url,
+        user_agent: &str,
+        rate_limit: Duration,
+        timeout: Duration,
+        proxy: Option<&Url>,
+    ) -> Result<Self> {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::USER_AGENT,
+            header::HeaderValue::from_str(user_agent)
+                .map_err(|_| Error::UserAgent(user_agent.to_owned()))?,
+        );
+        let mut builder = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(timeout);
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
+        }
+        let client = builder.build()?;
+
+        Ok(RegistryClient {
+            client,
+            proxy: proxy
+                .map(Url::to_string)
+                .or_else(|| env_proxy(base_url.scheme())),
+            base_url: with_trailing_slash(base_url),
+            rate_limit,
+            last_request: Mutex::new(None),
+        })
+    }
+
+    /// Client for crates.io
+    pub fn crates_io(
+        user_agent: &str,
+        rate_limit: Duration,
+        timeout: Duration,
+        proxy: Option<&Url>,
+    ) -> Result<Self> {
+        Self::new(
+            Url::parse(CRATES_IO_API).unwrap(),
+            user_agent,
+            rate_limit,
+            timeout,
+            proxy,
+        )
+    }
+
+    /// Client for the web API of the registry with the sparse index at `index`, e.g.
+    /// `sparse+https://mirror.example.com/index/`. The API is looked up in the `config.json` of the index.
+    pub async fn from_index(
+        index: &str,
+        user_agent: &str,
+        rate_limit: Duration,
+        timeout: Duration,
+        proxy: Option<&Url>,
+    ) -> Result<Self> {
+        // git indexes would have to be cloned to read their config.json
+        let Some(index_url) = index.strip_prefix("sparse+") else {
+            return Err(Error::UnsupportedSource(format!("git index {}", index)));
+        };
+        let index_url = Url::parse(index_url)
+            .map(with_trailing_slash)
+            .map_err(|err| Error::RegistryUrl(index.to_owned(), err))?;
+        let config_url = index_url
+            .join("config.json")
+            .map_err(|err| Error::RegistryUrl(index.to_owned(), err))?;
+        let mut registry = Self::new(index_url, user_agent, rate_limit, timeout, proxy)?;
+
+        registry.throttle().await;
+        let content = registry
+            .client
+            .get(config_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let IndexConfig { api } = serde_json::from_slice(&content)?;
+        let Some(api) = api else {
+            return Err(Error::UnsupportedSource(format!(
+                "registry {} without web API",
+                index
+            )));
+        };
+        registry.base_url = Url::parse(&api)
+            .map(with_trailing_slash)
+            .and_then(|url| url.join("api/v1/"))
+            .map_err(|err| Error::RegistryUrl(api.clone(), err))?;
+        Ok(registry)
+    }
+
+    /// The base URL of the API
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Wait until the rate limit allows the next request
+    async fn throttle(&self) {
+        let mut last_request = self.last_request.lock().await;
+        if let Some(last_request) = *last_request {
+            tokio::time::sleep_until(last_request + self.rate_limit).await;
+        }
+        *last_request = Some(Instant::now());
+    }
+
+    async fn fetch_versions(&self, crate_name: &str) -> Result<Vec<PublishedVersion>> {
+        let url = self
+            .base_url
+            .join("crates/")
+            .and_then(|url| url.join(crate_name))
+            .map_err(|err| Error::RegistryUrl(self.base_url.to_string(), err))?;
+
+        self.throttle().await;
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|err| match &self.proxy {
+                Some(proxy) if err.is_connect() => Error::Proxy(proxy.clone(), err),
+                _ => Error::Reqwest(err),
+            })?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after);
+            return Err(Error::RateLimited { retry_after });
+        }
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(Error::CrateNotFound(crate_name.to_owned()));
+        }
+        let response = response.error_for_status()?;
+        let content = response.bytes().await?;
+        let crate_data: CrateVersions = serde_json::from_slice(&content)?;
+        Ok(crate_data
+            .versions
+            .into_iter()
+            .map(PublishedVersion::from)
+            .collect())
+    }
+}
+
+impl fmt::Display for RegistryClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.base_url)
+    }
+}
+
+impl VersionSource for RegistryClient {
+    fn versions<'a>(&'a self, crate_name: &'a str) -> BoxFuture<'a, Result<Vec<PublishedVersion>>> {
+        self.fetch_versions(crate_name).boxed()
+    }
+
+    fn cache_key(&self, crate_name: &str) -> Option<String> {
+        match self.base_url.host_str() {
+            Some(host) if self.base_url.as_str() != CRATES_IO_API => {
+                Some(format!("{}/{}", host, crate_name))
+            }
+            _ => Some(crate_name.to_owned()),
+        }
+    }
+}
+
+/// The part of the `config.json` of an index that we are interested in
+#[derive(Deserialize)]
+struct IndexConfig {
+    api: Option<String>,
+}
+
+/// The part of the crate response of the API that we are interested in
+#[derive(Deserialize)]
+struct CrateVersions {
+    versions: Vec<Version>,
+}
+
+/// Parse the value of a `Retry-After` header, which is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    // a date in the past means that we can retry right away
+    Some(
+        (date.to_utc() - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// The proxy that reqwest takes from the environment for requests with `scheme`
+fn env_proxy(scheme: &str) -> Option<String> {
+    let scheme_var = format!("{}_PROXY", scheme.to_uppercase());
+    [
+        scheme_var.as_str(),
+        &scheme_var.to_lowercase(),
+        "ALL_PROXY",
+        "all_proxy",
+    ]
+    .into_iter()
+    .filter_map(|var| std::env::var(var).ok())
+    .find(|proxy| !proxy.is_empty())
+}
+
+/// `Url::join` replaces the last path segment unless the base ends with a slash
+fn with_trailing_slash(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cache_key() {
+        let client = |url| {
+            RegistryClient::new(
+                Url::parse(url).unwrap(),
+                "test",
+                Duration::ZERO,
+                Duration::from_secs(1),
+                None,
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            client(CRATES_IO_API).cache_key("serde").as_deref(),
+            Some("serde")
+        );
+        // mirrors do not share the entries of crates.io
+        assert_eq!(
+            client("https://mirror.example.com/api/v1/")
+                .cache_key("serde")
+                .as_deref(),
+            Some("mirror.example.com/serde")
+        );
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+
+        let in_an_hour = (chrono::Utc::now() + chrono::Duration::hours(1))
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        let wait = parse_retry_after(&in_an_hour).unwrap();
+        assert!(wait > Duration::from_secs(3590) && wait <= Duration::from_secs(3600));
+        // a date in the past means that we can retry right away
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("-5"), None);
+        assert_eq!(parse_retry_after(""), None);
+    }
+}
@@ -0,0 +1,42 @@
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// A published version of a crate, as far as it is relevant for downgrading
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublishedVersion {
+    pub num: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub yanked: bool,
+    pub rust_version: Option<String>,
+    pub checksum: Option<String>,
+}
+
+impl From<crates_io_api::Version> for PublishedVersion {
+    fn from(version: crates_io_api::Version) -> Self {
+        PublishedVersion {
+            num: version.num,
+            created_at: version.created_at,
+            updated_at: version.updated_at,
+            yanked: version.yanked,
+            rust_version: version.rust_version,
+            checksum: Some(version.checksum),
+        }
+    }
+}
+
+/// Somewhere to look up the published versions of crates, e.g. a registry API or an index
+pub trait VersionSource: fmt::Display + Send + Sync {
+    /// Fetch all published versions of `crate_name`
+    fn versions<'a>(&'a self, crate_name: &'a str) -> BoxFuture<'a, Result<Vec<PublishedVersion>>>;
+
+    /// Key under which the versions of `crate_name` are cached, or `None` if they should not be cached
+    fn cache_key(&self, _crate_name: &str) -> Option<String> {
+        None
+    }
+}